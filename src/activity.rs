@@ -1,33 +1,61 @@
 use std::{collections::HashMap, convert::TryInto};
 
 use crate::{
-    block::Block,
-    group::Group,
+    audit::Change,
+    block::{Block, Interval, Level},
+    group::{ActivityPolicy, Group, Groups},
+    holiday::{Calendar, Holidays},
+    quota::{Quota, Quotas},
+    role::{Permission, Role},
     session::Session,
+    template::Template,
     user::User,
-    util::{Abort, Error},
+    util::{abort_response, now, Abort, Conflicts, Error},
 };
 use actix_web::{web, HttpResponse};
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
-use sled::Transactional;
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionalTree},
+    Transactional,
+};
 
-const ACTIVITIES_TREE: &[u8] = b"activities";
-const ACTIVITIES_USER_TREE: &[u8] = b"activities_user";
+pub const ACTIVITIES_TREE: &[u8] = b"activities";
+pub const ACTIVITIES_USER_TREE: &[u8] = b"activities_user";
+pub const ACTIVITIES_CONFLICT_TREE: &[u8] = b"activities_conflict";
+pub const ACTIVITIES_GROUP_TREE: &[u8] = b"activities_group";
+/// The keys of the activities_user tree the other way around, to find the
+/// participants of an activity.
+pub const ACTIVITIES_PARTICIPANTS_TREE: &[u8] = b"activities_participants";
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Activity {
     group_id: u64,
-    block: Block,
+    block: Interval,
     description: String,
     min_participants: u32,
     max_participants: u32,
+    /// Seconds to keep free around the activity for all participants.
+    #[serde(default)]
+    buffer: u64,
     #[serde(default)]
     accepted: u32,
     #[serde(default)]
     pending: u32,
 }
 
+#[derive(Deserialize)]
+pub struct NewActivity {
+    group_id: u64,
+    block: Interval,
+    description: String,
+    /// Defaults to the settings of the group.
+    min_participants: Option<u32>,
+    max_participants: Option<u32>,
+    #[serde(default)]
+    buffer: u64,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq)]
 pub enum Status {
     Pending,
@@ -45,100 +73,1028 @@ impl Activity {
             Status::Pending
         }
     }
+
+    /// The block of this activity including the larger of its own buffer and
+    /// the given buffer of a participant.
+    fn padded(&self, buffer: u64) -> Interval {
+        self.block.expand(self.buffer.max(buffer))
+    }
+
+    pub fn group_id(&self) -> u64 {
+        self.group_id
+    }
+
+    pub fn set_group_id(&mut self, group_id: u64) {
+        self.group_id = group_id;
+    }
+
+    /// A `max_participants` of 0 means there is no limit.
+    fn over_capacity(&self) -> bool {
+        self.max_participants != 0 && self.accepted > self.max_participants
+    }
+}
+
+/// Everything keeping a user from taking part in activities.
+pub struct Availability<'a> {
+    blocks: Vec<Block>,
+    template: Template,
+    holidays: Holidays<'a>,
+}
+
+impl Availability<'_> {
+    /// The highest level of the blocks intersecting an activity.
+    fn conflict(&self, activity: &Activity) -> Option<Level> {
+        let block = activity.padded(self.template.buffer);
+        block.conflict(
+            self.blocks
+                .iter()
+                .chain(&self.template.blocks(&block, &self.holidays)),
+        )
+    }
+
+    /// The hard blocks intersecting an activity, including quiet hours and
+    /// blocked holidays.
+    fn hard_blocks(&self, activity: &Activity) -> Vec<Block> {
+        let block = activity.padded(self.template.buffer);
+        self.blocks
+            .iter()
+            .cloned()
+            .chain(self.template.blocks(&block, &self.holidays))
+            .filter(|b| b.level() == Level::Hard && block.intersects(&b.interval()))
+            .collect()
+    }
+}
+
+pub fn availability<'a>(
+    users_tree: &TransactionalTree,
+    templates_tree: &TransactionalTree,
+    holidays_user_tree: &TransactionalTree,
+    calendar: &'a Calendar,
+    user_id: u64,
+) -> Result<Availability<'a>, ConflictableTransactionError<Abort>> {
+    let user = users_tree
+        .get(user_id.to_be_bytes())?
+        .expect("Missing user_id");
+    let user: User = serde_json::from_slice(&user)
+        .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+    let template = Template::from_value(templates_tree.get(user_id.to_be_bytes())?)
+        .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+    let holidays = calendar
+        .holidays(holidays_user_tree.get(user_id.to_be_bytes())?)
+        .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+    Ok(Availability {
+        blocks: user.blocks,
+        template,
+        holidays,
+    })
+}
+
+/// Adds a user to the given activities they are not part of yet, as pending
+/// unless a hard block is in the way. Keeps the counters of the activities in
+/// sync.
+pub fn add_participant(
+    activities_tree: &TransactionalTree,
+    activities_user_tree: &TransactionalTree,
+    activities_conflict_tree: &TransactionalTree,
+    activities_participants_tree: &TransactionalTree,
+    user_id: u64,
+    activity_ids: &[u64],
+    availability: &Availability,
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    let mut key = Vec::with_capacity(16);
+    for &activity_id in activity_ids {
+        key.clear();
+        key.extend_from_slice(&user_id.to_be_bytes());
+        key.extend_from_slice(&activity_id.to_be_bytes());
+        if activities_user_tree.get(key.as_slice())?.is_some() {
+            continue;
+        }
+        let activity = match activities_tree.get(activity_id.to_be_bytes())? {
+            Some(activity) => activity,
+            None => continue,
+        };
+        let mut activity: Activity = serde_json::from_slice(&activity)
+            .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+        let intersect = availability.conflict(&activity);
+        let status = if intersect == Some(Level::Hard) {
+            Status::Denied
+        } else {
+            activity.pending += 1;
+            Status::Pending
+        };
+        if intersect == Some(Level::Tentative) {
+            activities_conflict_tree.insert(key.as_slice(), &[])?;
+        }
+        activities_user_tree.insert(
+            key.as_slice(),
+            serde_json::to_vec(&status)
+                .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?,
+        )?;
+        activities_participants_tree.insert(participant_key(activity_id, user_id), &[])?;
+        activities_tree.insert(
+            &activity_id.to_be_bytes(),
+            serde_json::to_vec(&activity)
+                .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?,
+        )?;
+    }
+    Ok(())
+}
+
+/// The key of a participant in the activities_participants tree.
+fn participant_key(activity_id: u64, user_id: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&activity_id.to_be_bytes());
+    key.extend_from_slice(&user_id.to_be_bytes());
+    key
+}
+
+/// Sets the status of a user for an activity and keeps the counters of the
+/// activity in sync. Returns the updated activity.
+pub fn set_status(
+    activities_tree: &TransactionalTree,
+    activities_user_tree: &TransactionalTree,
+    user_id: u64,
+    activity_id: u64,
+    status: &Status,
+) -> Result<Activity, ConflictableTransactionError<Abort>> {
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&user_id.to_be_bytes());
+    key.extend_from_slice(&activity_id.to_be_bytes());
+    let status_bytes = serde_json::to_vec(status)
+        .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+    let old_status = activities_user_tree
+        .insert(key, status_bytes)?
+        .ok_or(ConflictableTransactionError::Abort(Abort::NotFound))?;
+    let old_status: Status = serde_json::from_slice(&old_status)
+        .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+    let activity = activities_tree
+        .get(activity_id.to_be_bytes())?
+        .expect("Missing activity_id");
+    let mut activity: Activity = serde_json::from_slice(&activity)
+        .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+    if &old_status != status {
+        match old_status {
+            Status::Pending => activity.pending -= 1,
+            Status::Accepted => activity.accepted -= 1,
+            _ => (),
+        }
+        match status {
+            Status::Pending => activity.pending += 1,
+            Status::Accepted => activity.accepted += 1,
+            _ => (),
+        }
+        activities_tree.insert(
+            &activity_id.to_be_bytes(),
+            serde_json::to_vec(&activity)
+                .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?,
+        )?;
+    }
+    Ok(activity)
+}
+
+/// Adds activities stored before the group index existed to it.
+pub fn migrate(db: &sled::Db) -> Result<(), Error> {
+    let activities_tree = db.open_tree(ACTIVITIES_TREE)?;
+    let activities_group_tree = db.open_tree(ACTIVITIES_GROUP_TREE)?;
+    for res in activities_tree.iter() {
+        let (k, v) = res?;
+        let activity: Activity = serde_json::from_slice(&v)?;
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&activity.group_id.to_be_bytes());
+        key.extend_from_slice(&k);
+        activities_group_tree.insert(key, &[])?;
+    }
+    Ok(())
+}
+
+/// Adds the participants of activities stored before the
+/// activities_participants tree existed to it.
+pub fn migrate_participants(db: &sled::Db) -> Result<(), Error> {
+    let activities_user_tree = db.open_tree(ACTIVITIES_USER_TREE)?;
+    let activities_participants_tree = db.open_tree(ACTIVITIES_PARTICIPANTS_TREE)?;
+    for res in activities_user_tree.iter() {
+        let (k, _) = res?;
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&k[8..16]);
+        key.extend_from_slice(&k[0..8]);
+        activities_participants_tree.insert(key, &[])?;
+    }
+    Ok(())
+}
+
+/// Activities of a group which have not started yet.
+pub fn upcoming(db: &sled::Db, group_id: u64) -> Result<Vec<u64>, Error> {
+    let activities_tree = db.open_tree(ACTIVITIES_TREE)?;
+    let activities_group_tree = db.open_tree(ACTIVITIES_GROUP_TREE)?;
+    let now = now();
+    let mut ids = Vec::new();
+    for res in activities_group_tree.scan_prefix(group_id.to_be_bytes()) {
+        let (k, _) = res?;
+        let activity = activities_tree
+            .get(&k[8..16])?
+            .expect("Missing activity_id");
+        let activity: Activity = serde_json::from_slice(&activity)?;
+        if activity.block.start() > now {
+            ids.push(u64::from_be_bytes(k[8..16].try_into().unwrap()));
+        }
+    }
+    Ok(ids)
+}
+
+/// How many of the activities returned by `upcoming` still exist.
+pub fn remaining(
+    activities_tree: &TransactionalTree,
+    activity_ids: &[u64],
+) -> Result<u64, ConflictableTransactionError<Abort>> {
+    let mut remaining = 0;
+    for &activity_id in activity_ids {
+        if activities_tree.get(activity_id.to_be_bytes())?.is_some() {
+            remaining += 1;
+        }
+    }
+    Ok(remaining)
+}
+
+/// The activities of a group along with their participants as
+/// `(activity_id, user_id)` and the keys of its proposals.
+#[derive(Default)]
+pub struct GroupActivities {
+    ids: Vec<u64>,
+    rows: Vec<(u64, u64)>,
+    proposals: Vec<sled::IVec>,
+}
+
+/// Collects the activities of a group, since transactions can't scan.
+pub fn of_group(db: &sled::Db, group_id: u64) -> Result<GroupActivities, Error> {
+    let activities_participants_tree = db.open_tree(ACTIVITIES_PARTICIPANTS_TREE)?;
+    let activities_group_tree = db.open_tree(ACTIVITIES_GROUP_TREE)?;
+    let proposals_tree = db.open_tree(crate::proposal::PROPOSALS_TREE)?;
+    let ids = activities_group_tree
+        .scan_prefix(group_id.to_be_bytes())
+        .map(|res| -> Result<u64, Error> {
+            let (k, _) = res?;
+            Ok(u64::from_be_bytes(k[8..16].try_into().unwrap()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut rows = Vec::new();
+    for &activity_id in &ids {
+        for k in activities_participants_tree
+            .scan_prefix(activity_id.to_be_bytes())
+            .keys()
+        {
+            let user_id = u64::from_be_bytes(k?[8..16].try_into().unwrap());
+            rows.push((activity_id, user_id));
+        }
+    }
+    let proposals = proposals_tree
+        .scan_prefix(group_id.to_be_bytes())
+        .keys()
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(GroupActivities {
+        ids,
+        rows,
+        proposals,
+    })
+}
+
+/// Deletes the activities of a group along with the statuses of all
+/// participants and the proposals of the group on behalf of `deleted_by`.
+#[allow(clippy::too_many_arguments)]
+pub fn delete_all(
+    activities_tree: &TransactionalTree,
+    activities_user_tree: &TransactionalTree,
+    activities_conflict_tree: &TransactionalTree,
+    activities_participants_tree: &TransactionalTree,
+    activities_group_tree: &TransactionalTree,
+    proposals_tree: &TransactionalTree,
+    audit_log_tree: &TransactionalTree,
+    group_id: u64,
+    members: &HashMap<u64, Role>,
+    activities: &GroupActivities,
+    deleted_by: u64,
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    for proposal in &activities.proposals {
+        proposals_tree.remove(proposal)?;
+    }
+    let mut rows = activities.rows.clone();
+    // Members may have been added after the scan
+    for &activity_id in &activities.ids {
+        rows.extend(members.keys().map(|&user_id| (activity_id, user_id)));
+    }
+    let mut key = Vec::with_capacity(16);
+    for (activity_id, user_id) in rows {
+        key.clear();
+        key.extend_from_slice(&user_id.to_be_bytes());
+        key.extend_from_slice(&activity_id.to_be_bytes());
+        activities_user_tree.remove(key.as_slice())?;
+        activities_conflict_tree.remove(key.as_slice())?;
+        activities_participants_tree.remove(participant_key(activity_id, user_id))?;
+    }
+    for &activity_id in &activities.ids {
+        key.clear();
+        key.extend_from_slice(&group_id.to_be_bytes());
+        key.extend_from_slice(&activity_id.to_be_bytes());
+        activities_group_tree.remove(key.as_slice())?;
+        activities_tree.remove(&activity_id.to_be_bytes())?;
+        crate::audit::record(
+            audit_log_tree,
+            group_id,
+            deleted_by,
+            Change::ActivityDeleted { activity_id },
+        )?;
+    }
+    Ok(())
+}
+
+/// Moves the activities and proposals of a group to another group. The
+/// statuses of all participants are kept.
+pub fn move_all(
+    activities_tree: &TransactionalTree,
+    activities_group_tree: &TransactionalTree,
+    proposals_tree: &TransactionalTree,
+    group_id: u64,
+    new_group_id: u64,
+    activities: &GroupActivities,
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    for proposal in &activities.proposals {
+        crate::proposal::move_to(proposals_tree, proposal, new_group_id)?;
+    }
+    let mut key = Vec::with_capacity(16);
+    for &activity_id in &activities.ids {
+        let activity = match activities_tree.get(activity_id.to_be_bytes())? {
+            Some(activity) => activity,
+            None => continue,
+        };
+        let mut activity: Activity = serde_json::from_slice(&activity)
+            .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+        activity.group_id = new_group_id;
+        activities_tree.insert(
+            &activity_id.to_be_bytes(),
+            serde_json::to_vec(&activity)
+                .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?,
+        )?;
+        key.clear();
+        key.extend_from_slice(&group_id.to_be_bytes());
+        key.extend_from_slice(&activity_id.to_be_bytes());
+        activities_group_tree.remove(key.as_slice())?;
+        key.clear();
+        key.extend_from_slice(&new_group_id.to_be_bytes());
+        key.extend_from_slice(&activity_id.to_be_bytes());
+        activities_group_tree.insert(key.as_slice(), &[])?;
+    }
+    Ok(())
+}
+
+/// Removes a user from the given activities and keeps the counters of the
+/// activities in sync.
+pub fn remove_participant(
+    activities_tree: &TransactionalTree,
+    activities_user_tree: &TransactionalTree,
+    activities_conflict_tree: &TransactionalTree,
+    activities_participants_tree: &TransactionalTree,
+    user_id: u64,
+    activity_ids: &[u64],
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    let mut key = Vec::with_capacity(16);
+    for &activity_id in activity_ids {
+        key.clear();
+        key.extend_from_slice(&user_id.to_be_bytes());
+        key.extend_from_slice(&activity_id.to_be_bytes());
+        activities_conflict_tree.remove(key.as_slice())?;
+        activities_participants_tree.remove(participant_key(activity_id, user_id))?;
+        let status = match activities_user_tree.remove(key.as_slice())? {
+            Some(status) => status,
+            None => continue,
+        };
+        let status: Status = serde_json::from_slice(&status)
+            .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+        let activity = match activities_tree.get(activity_id.to_be_bytes())? {
+            Some(activity) => activity,
+            None => continue,
+        };
+        let mut activity: Activity = serde_json::from_slice(&activity)
+            .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+        match status {
+            Status::Pending => activity.pending -= 1,
+            Status::Accepted => activity.accepted -= 1,
+            Status::Denied => continue,
+        }
+        activities_tree.insert(
+            &activity_id.to_be_bytes(),
+            serde_json::to_vec(&activity)
+                .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?,
+        )?;
+    }
+    Ok(())
+}
+
+/// Activities of a user intersecting the given block which the user has not
+/// denied, taking the buffer of the user into account. The statuses have to
+/// be checked again inside of a transaction.
+pub fn intersecting(
+    db: &sled::Db,
+    user_id: u64,
+    block: &Interval,
+    buffer: u64,
+) -> Result<Vec<u64>, Error> {
+    let activities_tree = db.open_tree(ACTIVITIES_TREE)?;
+    let activities_user_tree = db.open_tree(ACTIVITIES_USER_TREE)?;
+    let mut activity_ids = Vec::new();
+    for res in activities_user_tree.scan_prefix(user_id.to_be_bytes()) {
+        let (k, v) = res?;
+        let status: Status = serde_json::from_slice(&v)?;
+        if status == Status::Denied {
+            continue;
+        }
+        let activity = activities_tree
+            .get(&k[8..16])?
+            .expect("Missing activity_id");
+        let activity: Activity = serde_json::from_slice(&activity)?;
+        if activity.padded(buffer).intersects(block) {
+            activity_ids.push(u64::from_be_bytes(k[8..16].try_into().unwrap()));
+        }
+    }
+    Ok(activity_ids)
+}
+
+/// Re-evaluates the given activities of a user after the user added a block.
+/// Intersecting tentative blocks only flag the conflict. For hard blocks
+/// pending activities are denied if `deny_pending` is set and accepted ones
+/// abort the transaction unless `keep_accepted` is set. Activities of
+/// archived groups are left as they are. Returns the activities which still
+/// intersect the block.
+#[allow(clippy::too_many_arguments)]
+pub fn block_added(
+    activities_tree: &TransactionalTree,
+    activities_user_tree: &TransactionalTree,
+    activities_conflict_tree: &TransactionalTree,
+    groups_tree: &TransactionalTree,
+    user_id: u64,
+    activity_ids: &[u64],
+    block: &Block,
+    buffer: u64,
+    deny_pending: bool,
+    keep_accepted: bool,
+) -> Result<Vec<u64>, ConflictableTransactionError<Abort>> {
+    let mut remaining = Vec::new();
+    let mut refused = Vec::new();
+    let mut key = Vec::with_capacity(16);
+    for &activity_id in activity_ids {
+        key.clear();
+        key.extend_from_slice(&user_id.to_be_bytes());
+        key.extend_from_slice(&activity_id.to_be_bytes());
+        let status: Status = match activities_user_tree.get(key.as_slice())? {
+            Some(status) => serde_json::from_slice(&status)
+                .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?,
+            None => continue,
+        };
+        // The activity may have been deleted since the scan
+        let activity = match activities_tree.get(activity_id.to_be_bytes())? {
+            Some(activity) => activity,
+            None => continue,
+        };
+        let activity: Activity = serde_json::from_slice(&activity)
+            .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+        if status == Status::Denied || !activity.padded(buffer).intersects(&block.interval()) {
+            continue;
+        }
+        if groups_tree
+            .group(activity.group_id)?
+            .is_some_and(|group| group.archived)
+        {
+            remaining.push(activity_id);
+            continue;
+        }
+        match (block.level(), status) {
+            (Level::Hard, Status::Pending) if deny_pending => {
+                set_status(
+                    activities_tree,
+                    activities_user_tree,
+                    user_id,
+                    activity_id,
+                    &Status::Denied,
+                )?;
+                activities_conflict_tree.remove(key.as_slice())?;
+            }
+            (Level::Hard, Status::Accepted) if !keep_accepted => refused.push(activity_id),
+            _ => {
+                activities_conflict_tree.insert(key.as_slice(), &[])?;
+                remaining.push(activity_id);
+            }
+        }
+    }
+    if !refused.is_empty() {
+        sled::transaction::abort(Abort::Conflict(Conflicts {
+            blocks: Vec::new(),
+            activities: refused,
+            groups: Vec::new(),
+        }))?;
+    }
+    Ok(remaining)
+}
+
+/// Inserts a new activity and invites all `members` of its group who may take
+/// part. The creator accepts right away unless something is in the way.
+#[allow(clippy::too_many_arguments)]
+pub fn insert(
+    activities_tree: &TransactionalTree,
+    activities_user_tree: &TransactionalTree,
+    activities_conflict_tree: &TransactionalTree,
+    activities_participants_tree: &TransactionalTree,
+    activities_group_tree: &TransactionalTree,
+    users_tree: &TransactionalTree,
+    templates_tree: &TransactionalTree,
+    holidays_user_tree: &TransactionalTree,
+    calendar: &Calendar,
+    members: &HashMap<u64, Role>,
+    user_id: u64,
+    activity: &Activity,
+) -> Result<u64, ConflictableTransactionError<Abort>> {
+    let activity_id = activities_tree.generate_id()?;
+
+    let mut key = Vec::with_capacity(16);
+    let mut pending = 0;
+    let mut accepted = 0;
+    for (&id, role) in members {
+        if !role.can(Permission::Participate) {
+            continue;
+        }
+        let availability =
+            availability(users_tree, templates_tree, holidays_user_tree, calendar, id)?;
+        // Hard blocks deny outright, tentative ones only flag the conflict
+        let intersect = availability.conflict(activity);
+        let status = match intersect {
+            Some(Level::Hard) => Status::Denied,
+            Some(Level::Tentative) => {
+                pending += 1;
+                Status::Pending
+            }
+            None if id == user_id => {
+                accepted += 1;
+                Status::Accepted
+            }
+            None => {
+                pending += 1;
+                Status::Pending
+            }
+        };
+        key.clear();
+        key.extend_from_slice(&id.to_be_bytes());
+        key.extend_from_slice(&activity_id.to_be_bytes());
+        if intersect == Some(Level::Tentative) {
+            activities_conflict_tree.insert(key.as_slice(), &[])?;
+        }
+        activities_user_tree.insert(
+            key.as_slice(),
+            serde_json::to_vec(&status)
+                .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?,
+        )?;
+        activities_participants_tree.insert(participant_key(activity_id, id), &[])?;
+    }
+
+    let mut activity = activity.clone();
+    activity.pending = pending;
+    activity.accepted = accepted;
+    activities_tree.insert(
+        &activity_id.to_be_bytes(),
+        serde_json::to_vec(&activity)
+            .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?,
+    )?;
+    key.clear();
+    key.extend_from_slice(&activity.group_id.to_be_bytes());
+    key.extend_from_slice(&activity_id.to_be_bytes());
+    activities_group_tree.insert(key.as_slice(), &[])?;
+    if activity.status() == Status::Denied {
+        sled::transaction::abort(Abort::Conflict(Conflicts::default()))
+    } else {
+        Ok(activity_id)
+    }
+}
+
+/// The result of posting an activity, depending on the activity policy of the
+/// group.
+enum Created {
+    Activity(u64),
+    Proposal(u64),
 }
 
 pub async fn create(
     session: web::Query<Session>,
     db: web::Data<sled::Db>,
-    activity: web::Json<Activity>,
+    calendar: web::Data<Calendar>,
+    quotas: web::Data<Quotas>,
+    activity: web::Json<NewActivity>,
 ) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let activity = activity.into_inner();
+    let activities_tree = db.open_tree(ACTIVITIES_TREE)?;
+    let activities_user_tree = db.open_tree(ACTIVITIES_USER_TREE)?;
+    let activities_conflict_tree = db.open_tree(ACTIVITIES_CONFLICT_TREE)?;
+    let activities_participants_tree = db.open_tree(ACTIVITIES_PARTICIPANTS_TREE)?;
+    let activities_group_tree = db.open_tree(ACTIVITIES_GROUP_TREE)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    let settings = match groups_tree.get(activity.group_id.to_be_bytes())? {
+        Some(group) => serde_json::from_slice::<Group>(&group)?.settings,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let activity = Activity {
+        group_id: activity.group_id,
+        block: activity.block,
+        description: activity.description,
+        min_participants: activity
+            .min_participants
+            .unwrap_or(settings.min_participants),
+        max_participants: activity
+            .max_participants
+            .unwrap_or(settings.max_participants),
+        buffer: activity.buffer,
+        accepted: 0,
+        pending: 0,
+    };
     if activity.max_participants != 0 && activity.max_participants < activity.min_participants {
         return Ok(HttpResponse::BadRequest().finish());
     }
+    let users_tree = db.open_tree(crate::user::USERS_TREE)?;
+    let templates_tree = db.open_tree(crate::template::TEMPLATES_TREE)?;
+    let holidays_user_tree = db.open_tree(crate::holiday::HOLIDAYS_USER_TREE)?;
+    let proposals_tree = db.open_tree(crate::proposal::PROPOSALS_TREE)?;
+    let activities_created_tree = db.open_tree(crate::quota::ACTIVITIES_CREATED_TREE)?;
+    // The group may change between reading it and the transaction
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        let snapshot = crate::group::snapshot(&db, activity.group_id)?;
+        let upcoming = upcoming(&db, activity.group_id)?;
+        let result = (
+            &activities_tree,
+            &activities_user_tree,
+            &activities_conflict_tree,
+            &activities_participants_tree,
+            &activities_group_tree,
+            &groups_tree,
+            &group_members_tree,
+            &users_tree,
+            &templates_tree,
+            &holidays_user_tree,
+            &proposals_tree,
+            &activities_created_tree,
+        )
+            .transaction(
+                |(
+                    activities_tree,
+                    activities_user_tree,
+                    activities_conflict_tree,
+                    activities_participants_tree,
+                    activities_group_tree,
+                    groups_tree,
+                    group_members_tree,
+                    users_tree,
+                    templates_tree,
+                    holidays_user_tree,
+                    proposals_tree,
+                    activities_created_tree,
+                )| {
+                    let members = crate::group::current_members(
+                        groups_tree,
+                        group_members_tree,
+                        activity.group_id,
+                        &snapshot,
+                    )?;
+                    let group = crate::group::bump_version(groups_tree, activity.group_id)?;
+                    let role = match crate::group::role_in(
+                        groups_tree,
+                        group_members_tree,
+                        activity.group_id,
+                        &group,
+                        user_id,
+                    )? {
+                        Some(role) => role,
+                        None => return sled::transaction::abort(Abort::NotFound),
+                    };
+                    if group.archived || !group.settings.activities.allows(role) {
+                        return sled::transaction::abort(Abort::NotAllowed);
+                    }
+                    quotas.check(
+                        Quota::OpenActivities,
+                        remaining(activities_tree, &upcoming)?,
+                    )?;
+                    quotas.count_activity(activities_created_tree, user_id)?;
+                    if group.settings.activities == ActivityPolicy::Approval
+                        && !role.can(Permission::ApproveActivities)
+                    {
+                        return Ok(Created::Proposal(crate::proposal::propose(
+                            proposals_tree,
+                            user_id,
+                            &activity,
+                        )?));
+                    }
+                    Ok(Created::Activity(insert(
+                        activities_tree,
+                        activities_user_tree,
+                        activities_conflict_tree,
+                        activities_participants_tree,
+                        activities_group_tree,
+                        users_tree,
+                        templates_tree,
+                        holidays_user_tree,
+                        &calendar,
+                        &members,
+                        user_id,
+                        &activity,
+                    )?))
+                },
+            );
+        if attempts == crate::group::ATTEMPTS
+            || !matches!(
+                result,
+                Err(sled::transaction::TransactionError::Abort(Abort::Stale))
+            )
+        {
+            break result;
+        }
+    };
+    match result {
+        Ok(Created::Activity(activity_id)) => Ok(HttpResponse::Ok().json(activity_id)),
+        Ok(Created::Proposal(proposal_id)) => Ok(HttpResponse::Accepted().json(proposal_id)),
+        Err(err) => abort_response(err),
+    }
+}
+
+/// Users with a status for an activity, since transactions can't scan.
+fn participants(db: &sled::Db, activity_id: u64) -> Result<Vec<u64>, Error> {
+    let activities_participants_tree = db.open_tree(ACTIVITIES_PARTICIPANTS_TREE)?;
+    activities_participants_tree
+        .scan_prefix(activity_id.to_be_bytes())
+        .keys()
+        .map(|k| Ok(u64::from_be_bytes(k?[8..16].try_into().unwrap())))
+        .collect()
+}
+
+/// The other activities each participant has not denied which intersect the
+/// new block of an activity, since transactions can't scan.
+fn booked(
+    db: &sled::Db,
+    activity_id: u64,
+    block: &Interval,
+    participants: &[u64],
+) -> Result<HashMap<u64, Vec<u64>>, Error> {
+    let activities_tree = db.open_tree(ACTIVITIES_TREE)?;
+    let templates_tree = db.open_tree(crate::template::TEMPLATES_TREE)?;
+    let buffer = match activities_tree.get(activity_id.to_be_bytes())? {
+        Some(activity) => serde_json::from_slice::<Activity>(&activity)?.buffer,
+        None => 0,
+    };
+    let mut booked = HashMap::new();
+    for &user_id in participants {
+        let template = Template::from_value(templates_tree.get(user_id.to_be_bytes())?)?;
+        let mut activity_ids = intersecting(db, user_id, block, buffer.max(template.buffer))?;
+        activity_ids.retain(|&id| id != activity_id);
+        booked.insert(user_id, activity_ids);
+    }
+    Ok(booked)
+}
+
+/// Whether a user accepted any of the given activities.
+fn accepted_any(
+    activities_user_tree: &TransactionalTree,
+    user_id: u64,
+    activity_ids: &[u64],
+) -> Result<bool, ConflictableTransactionError<Abort>> {
+    let mut key = Vec::with_capacity(16);
+    for &activity_id in activity_ids {
+        key.clear();
+        key.extend_from_slice(&user_id.to_be_bytes());
+        key.extend_from_slice(&activity_id.to_be_bytes());
+        if let Some(status) = activities_user_tree.get(key.as_slice())? {
+            let status: Status = serde_json::from_slice(&status)
+                .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+            if status == Status::Accepted {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Changes to an activity. All fields are optional.
+#[derive(Deserialize)]
+pub struct ActivityChanges {
+    description: Option<String>,
+    block: Option<Interval>,
+    min_participants: Option<u32>,
+    max_participants: Option<u32>,
+}
+
+/// Changes an activity. A new block is checked against the blocks of all
+/// participants again: hard blocks deny, tentative ones and other accepted
+/// activities at the new time flag the conflict and turn acceptance back into
+/// pending. Participants denied because of a hard block at the old time are
+/// invited again if the new time is free for them. The counters are
+/// recomputed from the statuses of all participants.
+pub async fn update(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    calendar: web::Data<Calendar>,
+    activity_id: web::Path<u64>,
+    changes: web::Json<ActivityChanges>,
+) -> Result<HttpResponse, Error> {
     let user_id: u64 = session.get(&db)?;
-    let activity = activity.into_inner();
+    let activity_id = activity_id.into_inner();
     let activities_tree = db.open_tree(ACTIVITIES_TREE)?;
     let activities_user_tree = db.open_tree(ACTIVITIES_USER_TREE)?;
+    let activities_conflict_tree = db.open_tree(ACTIVITIES_CONFLICT_TREE)?;
     let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
     let users_tree = db.open_tree(crate::user::USERS_TREE)?;
-    let result = (
-        &activities_tree,
-        &activities_user_tree,
-        &groups_tree,
-        &users_tree,
-    )
-        .transaction(
-            |(activities_tree, activities_user_tree, groups_tree, users_tree)| {
-                let group = groups_tree.get(activity.group_id.to_be_bytes())?.ok_or(
-                    sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
-                )?;
-                let group: Group = serde_json::from_slice(&group).map_err(|err| {
-                    sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(err))
-                })?;
-                if !group.users.contains_key(&user_id) {
-                    sled::transaction::abort(Abort::NotFound)?;
-                }
-                let activity_id = activities_tree.generate_id()?;
-
-                let mut key = Vec::with_capacity(16);
-                let mut pending = 0;
-                let mut accepted = 0;
-                for (id, _) in group.users {
-                    let user = users_tree.get(id.to_be_bytes())?.expect("Missing user_id");
-                    let user: User = serde_json::from_slice(&user).map_err(|err| {
-                        sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(
-                            err,
-                        ))
-                    })?;
-                    let intersect = user.blocks.iter().any(|b| activity.block.intersects(b));
-                    let status = if intersect {
-                        Status::Denied
-                    } else if id == user_id {
-                        accepted += 1;
-                        Status::Accepted
-                    } else {
-                        pending += 1;
-                        Status::Pending
-                    };
-                    key.clear();
-                    key.extend_from_slice(&id.to_be_bytes());
-                    key.extend_from_slice(&activity_id.to_be_bytes());
-                    activities_user_tree.insert(
-                        key.as_slice(),
-                        serde_json::to_vec(&status).map_err(|err| {
+    let templates_tree = db.open_tree(crate::template::TEMPLATES_TREE)?;
+    let holidays_user_tree = db.open_tree(crate::holiday::HOLIDAYS_USER_TREE)?;
+    let audit_log_tree = db.open_tree(crate::audit::AUDIT_LOG_TREE)?;
+    // The participants may change between reading them and the transaction
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        let group_id = match activities_tree.get(activity_id.to_be_bytes())? {
+            Some(activity) => serde_json::from_slice::<Activity>(&activity)?.group_id,
+            None => return Ok(HttpResponse::NotFound().finish()),
+        };
+        let snapshot = crate::group::snapshot(&db, group_id)?;
+        let participants = participants(&db, activity_id)?;
+        let booked = match &changes.block {
+            Some(block) => booked(&db, activity_id, block, &participants)?,
+            None => HashMap::new(),
+        };
+        let result = (
+            &activities_tree,
+            &activities_user_tree,
+            &activities_conflict_tree,
+            &groups_tree,
+            &group_members_tree,
+            &users_tree,
+            &templates_tree,
+            &holidays_user_tree,
+            &audit_log_tree,
+        )
+            .transaction(
+                |(
+                    activities_tree,
+                    activities_user_tree,
+                    activities_conflict_tree,
+                    groups_tree,
+                    group_members_tree,
+                    users_tree,
+                    templates_tree,
+                    holidays_user_tree,
+                    audit_log_tree,
+                )| {
+                    let activity = activities_tree.get(activity_id.to_be_bytes())?.ok_or(
+                        sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
+                    )?;
+                    let mut activity: Activity =
+                        serde_json::from_slice(&activity).map_err(|err| {
+                            sled::transaction::ConflictableTransactionError::Abort(
+                                Abort::SerdeError(err),
+                            )
+                        })?;
+                    // Participants only join or leave along with the group
+                    if activity.group_id != group_id {
+                        sled::transaction::abort(Abort::Stale)?;
+                    }
+                    crate::group::check_snapshot(groups_tree, group_id, &snapshot)?;
+                    let group = groups_tree.group(activity.group_id)?.ok_or(
+                        sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
+                    )?;
+                    match crate::group::role_in(
+                        groups_tree,
+                        group_members_tree,
+                        activity.group_id,
+                        &group,
+                        user_id,
+                    )? {
+                        Some(role) if role.can(Permission::EditActivities) && !group.archived => {}
+                        Some(_) => sled::transaction::abort(Abort::NotAllowed)?,
+                        None => sled::transaction::abort(Abort::NotFound)?,
+                    }
+                    let old = activity.clone();
+                    if let Some(description) = &changes.description {
+                        activity.description = description.clone();
+                    }
+                    if let Some(min_participants) = changes.min_participants {
+                        activity.min_participants = min_participants;
+                    }
+                    if let Some(max_participants) = changes.max_participants {
+                        activity.max_participants = max_participants;
+                    }
+                    if activity.max_participants != 0
+                        && activity.max_participants < activity.min_participants
+                    {
+                        return Ok(false);
+                    }
+                    if let Some(block) = &changes.block {
+                        activity.block = *block;
+                    }
+                    activity.accepted = 0;
+                    activity.pending = 0;
+                    let mut key = Vec::with_capacity(16);
+                    for &participant in &participants {
+                        key.clear();
+                        key.extend_from_slice(&participant.to_be_bytes());
+                        key.extend_from_slice(&activity_id.to_be_bytes());
+                        let mut status: Status = match activities_user_tree.get(key.as_slice())? {
+                            Some(status) => serde_json::from_slice(&status).map_err(|err| {
+                                sled::transaction::ConflictableTransactionError::Abort(
+                                    Abort::SerdeError(err),
+                                )
+                            })?,
+                            None => continue,
+                        };
+                        if changes.block.is_some() {
+                            let availability = availability(
+                                users_tree,
+                                templates_tree,
+                                holidays_user_tree,
+                                &calendar,
+                                participant,
+                            )?;
+                            let intersect = availability.conflict(&activity);
+                            let double_booked = match booked.get(&participant) {
+                                Some(activity_ids) => {
+                                    accepted_any(activities_user_tree, participant, activity_ids)?
+                                }
+                                None => false,
+                            };
+                            if intersect == Some(Level::Tentative)
+                                || (intersect.is_none() && double_booked)
+                            {
+                                activities_conflict_tree.insert(key.as_slice(), &[])?;
+                            } else {
+                                activities_conflict_tree.remove(key.as_slice())?;
+                            }
+                            let reset = match (intersect, &status) {
+                                (Some(Level::Hard), Status::Denied) => None,
+                                (Some(Level::Hard), _) => Some(Status::Denied),
+                                (_, Status::Accepted) if intersect.is_some() || double_booked => {
+                                    Some(Status::Pending)
+                                }
+                                // Denied because of a hard block at the old time
+                                (_, Status::Denied)
+                                    if availability.conflict(&old) == Some(Level::Hard) =>
+                                {
+                                    Some(Status::Pending)
+                                }
+                                _ => None,
+                            };
+                            if let Some(reset) = reset {
+                                activities_user_tree.insert(
+                                    key.as_slice(),
+                                    serde_json::to_vec(&reset).map_err(|err| {
+                                        sled::transaction::ConflictableTransactionError::Abort(
+                                            Abort::SerdeError(err),
+                                        )
+                                    })?,
+                                )?;
+                                status = reset;
+                            }
+                        }
+                        match status {
+                            Status::Pending => activity.pending += 1,
+                            Status::Accepted => activity.accepted += 1,
+                            Status::Denied => (),
+                        }
+                    }
+                    if activity.over_capacity() || activity.status() == Status::Denied {
+                        sled::transaction::abort(Abort::Conflict(Conflicts::default()))?;
+                    }
+                    activities_tree.insert(
+                        &activity_id.to_be_bytes(),
+                        serde_json::to_vec(&activity).map_err(|err| {
                             sled::transaction::ConflictableTransactionError::Abort(
                                 Abort::SerdeError(err),
                             )
                         })?,
                     )?;
-                }
-
-                let mut activity = activity.clone();
-                activity.pending = pending;
-                activity.accepted = accepted;
-                activities_tree.insert(
-                    &activity_id.to_be_bytes(),
-                    serde_json::to_vec(&activity).map_err(|err| {
-                        sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(
-                            err,
-                        ))
-                    })?,
-                )?;
-                if activity.status() == Status::Denied {
-                    sled::transaction::abort(Abort::NotAllowed)
-                } else {
-                    Ok(activity_id)
-                }
-            },
-        );
+                    crate::audit::record(
+                        audit_log_tree,
+                        activity.group_id,
+                        user_id,
+                        Change::ActivityEdited { activity_id },
+                    )?;
+                    Ok(true)
+                },
+            );
+        if attempts == crate::group::ATTEMPTS
+            || !matches!(
+                result,
+                Err(sled::transaction::TransactionError::Abort(Abort::Stale))
+            )
+        {
+            break result;
+        }
+    };
     match result {
-        Ok(activity_id) => Ok(HttpResponse::Ok().json(activity_id)),
-        Err(sled::transaction::TransactionError::Storage(err)) => Err(Error::SledError(err)),
-        Err(sled::transaction::TransactionError::Abort(abort)) => match abort {
-            Abort::NotFound => Ok(HttpResponse::NotFound().finish()),
-            Abort::NotAllowed => Ok(HttpResponse::Conflict().finish()),
-            Abort::SerdeError(err) => Err(Error::SerdeError(err)),
-        },
+        Ok(true) => Ok(HttpResponse::Ok().finish()),
+        Ok(false) => Ok(HttpResponse::BadRequest().finish()),
+        Err(err) => abort_response(err),
     }
 }
 
@@ -146,6 +1102,7 @@ pub async fn create(
 pub struct ActivityStats {
     activity: Box<RawValue>,
     status: Status,
+    conflict: bool,
 }
 
 pub async fn list(
@@ -155,6 +1112,7 @@ pub async fn list(
     let user_id: u64 = session.get(&db)?;
     let activities_tree = db.open_tree(ACTIVITIES_TREE)?;
     let activities_user_tree = db.open_tree(ACTIVITIES_USER_TREE)?;
+    let activities_conflict_tree = db.open_tree(ACTIVITIES_CONFLICT_TREE)?;
     let activities = activities_user_tree
         .scan_prefix(user_id.to_be_bytes())
         .map(|res| -> Result<_, Error> {
@@ -165,7 +1123,15 @@ pub async fn list(
                 .get(activity_id.to_be_bytes())?
                 .expect("Missing activity_id");
             let activity = serde_json::from_slice(&activity)?;
-            Ok((activity_id, ActivityStats { activity, status }))
+            let conflict = activities_conflict_tree.contains_key(&k)?;
+            Ok((
+                activity_id,
+                ActivityStats {
+                    activity,
+                    status,
+                    conflict,
+                },
+            ))
         })
         .collect::<Result<HashMap<_, _>, _>>()?;
     Ok(HttpResponse::Ok().json(activities))
@@ -175,76 +1141,276 @@ pub async fn list(
 pub struct StatusChange {
     activity_id: u64,
     status: Status,
+    #[serde(default)]
+    force: bool,
 }
 
 pub async fn change_status(
     session: web::Query<Session>,
     db: web::Data<sled::Db>,
+    calendar: web::Data<Calendar>,
     params: web::Json<StatusChange>,
 ) -> Result<HttpResponse, Error> {
     let user_id: u64 = session.get(&db)?;
     let activities_user_tree = db.open_tree(ACTIVITIES_USER_TREE)?;
     let activities_tree = db.open_tree(ACTIVITIES_TREE)?;
+    let activities_conflict_tree = db.open_tree(ACTIVITIES_CONFLICT_TREE)?;
+    let users_tree = db.open_tree(crate::user::USERS_TREE)?;
+    let templates_tree = db.open_tree(crate::template::TEMPLATES_TREE)?;
+    let holidays_user_tree = db.open_tree(crate::holiday::HOLIDAYS_USER_TREE)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
     let mut key = Vec::with_capacity(16);
-    let status = serde_json::to_vec(&params.status)?;
     key.extend_from_slice(&user_id.to_be_bytes());
     key.extend_from_slice(&params.activity_id.to_be_bytes());
-    let result = (&activities_tree, &activities_user_tree).transaction(
-        |(activities_tree, activities_user_tree)| {
-            if let Some(old_status) =
-                activities_user_tree.insert(key.as_slice(), status.as_slice())?
-            {
-                let old_status: Status = serde_json::from_slice(&old_status).map_err(|err| {
-                    sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(err))
-                })?;
-                if old_status != params.status {
-                    let activity = activities_tree
-                        .get(params.activity_id.to_be_bytes())?
-                        .expect("Missing activity_id");
-                    let mut activity: Activity =
-                        serde_json::from_slice(&activity).map_err(|err| {
-                            sled::transaction::ConflictableTransactionError::Abort(
-                                Abort::SerdeError(err),
-                            )
-                        })?;
-                    match old_status {
-                        Status::Pending => activity.pending -= 1,
-                        Status::Accepted => activity.accepted -= 1,
-                        _ => (),
+    let check = params.status == Status::Accepted && !params.force;
+    let mut attempts = 0;
+    // The activity may be moved between reading it and the transaction
+    let result = loop {
+        attempts += 1;
+        let (group_id, scanned, activity_ids) =
+            match activities_tree.get(params.activity_id.to_be_bytes())? {
+                Some(activity) => {
+                    let activity: Activity = serde_json::from_slice(&activity)?;
+                    if check {
+                        let template =
+                            Template::from_value(templates_tree.get(user_id.to_be_bytes())?)?;
+                        let buffer = activity.buffer.max(template.buffer);
+                        let mut activity_ids = intersecting(&db, user_id, &activity.block, buffer)?;
+                        activity_ids.retain(|&id| id != params.activity_id);
+                        (
+                            activity.group_id,
+                            Some((activity.block, buffer)),
+                            activity_ids,
+                        )
+                    } else {
+                        (activity.group_id, None, Vec::new())
                     }
-                    match params.status {
-                        Status::Pending => activity.pending += 1,
-                        Status::Accepted => activity.accepted += 1,
-                        _ => (),
+                }
+                None => return Ok(HttpResponse::NotFound().finish()),
+            };
+        let result = (
+            &activities_tree,
+            &activities_user_tree,
+            &activities_conflict_tree,
+            &users_tree,
+            &templates_tree,
+            &holidays_user_tree,
+            &groups_tree,
+        )
+            .transaction(
+                |(
+                    activities_tree,
+                    activities_user_tree,
+                    activities_conflict_tree,
+                    users_tree,
+                    templates_tree,
+                    holidays_user_tree,
+                    groups_tree,
+                )| {
+                    // Answers to activities of archived groups are frozen
+                    if groups_tree
+                        .group(group_id)?
+                        .is_some_and(|group| group.archived)
+                    {
+                        return Ok(None);
                     }
-                    if activity.accepted > activity.max_participants {
-                        sled::transaction::abort(Abort::NotAllowed)?;
+                    // Refuse double bookings unless forced
+                    if check {
+                        let activity = activities_tree
+                            .get(params.activity_id.to_be_bytes())?
+                            .ok_or(ConflictableTransactionError::Abort(Abort::NotFound))?;
+                        let activity: Activity =
+                            serde_json::from_slice(&activity).map_err(|err| {
+                                sled::transaction::ConflictableTransactionError::Abort(
+                                    Abort::SerdeError(err),
+                                )
+                            })?;
+                        let availability = availability(
+                            users_tree,
+                            templates_tree,
+                            holidays_user_tree,
+                            &calendar,
+                            user_id,
+                        )?;
+                        // The overlaps were found for the time read before
+                        if Some((
+                            activity.block,
+                            activity.buffer.max(availability.template.buffer),
+                        )) != scanned
+                        {
+                            sled::transaction::abort(Abort::Stale)?;
+                        }
+                        let mut conflicts = Conflicts {
+                            blocks: availability.hard_blocks(&activity),
+                            activities: Vec::new(),
+                            groups: Vec::new(),
+                        };
+                        let mut other_key = Vec::with_capacity(16);
+                        for &activity_id in &activity_ids {
+                            other_key.clear();
+                            other_key.extend_from_slice(&user_id.to_be_bytes());
+                            other_key.extend_from_slice(&activity_id.to_be_bytes());
+                            if let Some(status) = activities_user_tree.get(other_key.as_slice())? {
+                                let status: Status =
+                                    serde_json::from_slice(&status).map_err(|err| {
+                                        sled::transaction::ConflictableTransactionError::Abort(
+                                            Abort::SerdeError(err),
+                                        )
+                                    })?;
+                                if status == Status::Accepted {
+                                    conflicts.activities.push(activity_id);
+                                }
+                            }
+                        }
+                        if !conflicts.blocks.is_empty() || !conflicts.activities.is_empty() {
+                            sled::transaction::abort(Abort::Conflict(conflicts))?;
+                        }
                     }
-                    activities_tree.insert(
-                        &params.activity_id.to_be_bytes(),
-                        serde_json::to_vec(&activity).map_err(|err| {
-                            sled::transaction::ConflictableTransactionError::Abort(
-                                Abort::SerdeError(err),
-                            )
-                        })?,
+                    let activity = set_status(
+                        activities_tree,
+                        activities_user_tree,
+                        user_id,
+                        params.activity_id,
+                        &params.status,
                     )?;
-                    Ok(activity.status())
-                } else {
-                    Ok(Status::Pending)
-                }
-            } else {
-                sled::transaction::abort(Abort::NotFound)
-            }
-        },
-    );
+                    // An explicit answer resolves a tentative conflict
+                    activities_conflict_tree.remove(key.as_slice())?;
+                    if activity.over_capacity() {
+                        sled::transaction::abort(Abort::NotAllowed)?;
+                    }
+                    Ok(Some(activity.status()))
+                },
+            );
+        if attempts == crate::group::ATTEMPTS
+            || !matches!(
+                result,
+                Err(sled::transaction::TransactionError::Abort(Abort::Stale))
+            )
+        {
+            break result;
+        }
+    };
     match result {
         // TODO
-        Ok(_status) => Ok(HttpResponse::Ok().finish()),
-        Err(sled::transaction::TransactionError::Storage(err)) => Err(Error::SledError(err)),
-        Err(sled::transaction::TransactionError::Abort(abort)) => match abort {
-            Abort::NotFound => Ok(HttpResponse::NotFound().finish()),
-            Abort::NotAllowed => Ok(HttpResponse::Conflict().finish()),
-            Abort::SerdeError(err) => Err(Error::SerdeError(err)),
-        },
+        Ok(Some(_status)) => Ok(HttpResponse::Ok().finish()),
+        Ok(None) => Ok(HttpResponse::Forbidden().finish()),
+        Err(sled::transaction::TransactionError::Abort(Abort::NotAllowed)) => {
+            Ok(HttpResponse::Conflict().finish())
+        }
+        Err(err) => abort_response(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{activity, calendar, db, group, join, run, TestUser};
+
+    fn status(db: &sled::Db, user: &TestUser, activity_id: u64) -> Status {
+        let mut key = user.id.to_be_bytes().to_vec();
+        key.extend_from_slice(&activity_id.to_be_bytes());
+        let status = db
+            .open_tree(ACTIVITIES_USER_TREE)
+            .unwrap()
+            .get(&key)
+            .unwrap()
+            .unwrap();
+        serde_json::from_slice(&status).unwrap()
+    }
+
+    fn answer(db: &web::Data<sled::Db>, user: &TestUser, activity_id: u64, status: &str) {
+        let params = serde_json::from_value(serde_json::json!({
+            "activity_id": activity_id,
+            "status": status,
+        }))
+        .unwrap();
+        let response = run(change_status(
+            user.session(),
+            db.clone(),
+            calendar(),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert!(response.status().is_success());
+    }
+
+    /// Moves an activity to start `start` seconds from now.
+    fn move_to(db: &web::Data<sled::Db>, by: &TestUser, activity_id: u64, start: u64) {
+        let start = now() + start;
+        let changes = serde_json::from_value(serde_json::json!({
+            "block": {"start": start, "end": start + 3600},
+        }))
+        .unwrap();
+        let response = run(update(
+            by.session(),
+            db.clone(),
+            calendar(),
+            web::Path::from(activity_id),
+            web::Json(changes),
+        ))
+        .unwrap();
+        assert!(response.status().is_success());
+    }
+
+    #[test]
+    fn moving_onto_an_accepted_activity_asks_again() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let member = TestUser::new(&db, "member");
+        let group_id = group(&db, &owner);
+        join(&db, group_id, &owner, &member);
+        let first = activity(&db, group_id, &owner, 86400);
+        let second = activity(&db, group_id, &owner, 3 * 86400);
+        answer(&db, &member, first, "Accepted");
+        answer(&db, &member, second, "Accepted");
+
+        move_to(&db, &owner, second, 86400);
+        assert!(status(&db, &owner, second) == Status::Pending);
+        assert!(status(&db, &member, second) == Status::Pending);
+        assert!(status(&db, &member, first) == Status::Accepted);
+        let mut key = member.id.to_be_bytes().to_vec();
+        key.extend_from_slice(&second.to_be_bytes());
+        assert!(db
+            .open_tree(ACTIVITIES_CONFLICT_TREE)
+            .unwrap()
+            .contains_key(&key)
+            .unwrap());
+    }
+
+    #[test]
+    fn moving_away_from_a_block_invites_again() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let blocked = TestUser::new(&db, "blocked");
+        let declined = TestUser::new(&db, "declined");
+        let group_id = group(&db, &owner);
+        join(&db, group_id, &owner, &blocked);
+        join(&db, group_id, &owner, &declined);
+        let start = now() + 86400;
+        let options = serde_json::from_value(serde_json::json!({})).unwrap();
+        let response = run(crate::user::add_block(
+            db.clone(),
+            blocked.session(),
+            web::Query(options),
+            web::Json(Block::new(start - 3600, start + 7200)),
+        ))
+        .unwrap();
+        assert!(response.status().is_success());
+        let activity_id = activity(&db, group_id, &owner, 86400);
+        assert!(status(&db, &blocked, activity_id) == Status::Denied);
+        answer(&db, &declined, activity_id, "Denied");
+
+        move_to(&db, &owner, activity_id, 2 * 86400);
+        assert!(status(&db, &blocked, activity_id) == Status::Pending);
+        assert!(status(&db, &declined, activity_id) == Status::Denied);
+        let activities_tree = db.open_tree(ACTIVITIES_TREE).unwrap();
+        let activity: Activity = serde_json::from_slice(
+            &activities_tree
+                .get(activity_id.to_be_bytes())
+                .unwrap()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!((activity.accepted, activity.pending), (1, 1));
     }
 }
@@ -0,0 +1,204 @@
+use crate::{
+    group::Groups,
+    role::{Permission, Role},
+    session::Session,
+    util::{now, Abort, Error},
+};
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionalTree};
+use std::convert::TryInto;
+
+pub const AUDIT_LOG_TREE: &[u8] = b"audit_log";
+
+#[derive(Serialize, Deserialize)]
+pub enum Change {
+    /// A user joined the group or was added to it.
+    MemberAdded { user_id: u64, role: Role },
+    /// A member left the group or was removed from it.
+    MemberRemoved { user_id: u64 },
+    /// The role of a member changed.
+    RoleChanged { user_id: u64, role: Role },
+    /// The group was renamed.
+    Renamed { name: String },
+    /// An activity of the group was deleted.
+    ActivityDeleted { activity_id: u64 },
+    /// An activity of the group was changed.
+    ActivityEdited { activity_id: u64 },
+    /// The group was made read-only.
+    Archived,
+    /// The group was made writable again.
+    Unarchived,
+    /// Another group was merged into the group and deleted.
+    Merged { group_id: u64, name: String },
+    /// The group was deleted or merged into another one. `admins` may still
+    /// read the log.
+    Deleted { admins: Vec<u64> },
+}
+
+/// A change to a group, made by the user `user_id`.
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    time: u64,
+    user_id: u64,
+    change: Change,
+}
+
+/// Appends an entry to the log of a group. Entries are never changed or
+/// removed, not even when the group is deleted.
+pub fn record(
+    audit_log_tree: &TransactionalTree,
+    group_id: u64,
+    user_id: u64,
+    change: Change,
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    let entry_id = audit_log_tree.generate_id()?;
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&group_id.to_be_bytes());
+    key.extend_from_slice(&entry_id.to_be_bytes());
+    let entry = serde_json::to_vec(&Entry {
+        time: now(),
+        user_id,
+        change,
+    })
+    .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+    audit_log_tree.insert(key, entry)?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct LogParams {
+    before: Option<u64>,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    50
+}
+
+const MAX_LIMIT: usize = 200;
+
+#[derive(Serialize)]
+pub struct LogEntry {
+    entry_id: u64,
+    #[serde(flatten)]
+    entry: Entry,
+}
+
+/// Lists the log of a group, newest entries first. Older entries are fetched
+/// by passing the id of the last entry as `before`.
+pub async fn list(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    group_id: web::Path<u64>,
+    params: web::Query<LogParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let group_id = group_id.into_inner();
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    let audit_log_tree = db.open_tree(AUDIT_LOG_TREE)?;
+    let prefix = group_id.to_be_bytes();
+    match groups_tree.group(group_id)? {
+        Some(group) => {
+            match crate::group::role_in(
+                &groups_tree,
+                &group_members_tree,
+                group_id,
+                &group,
+                user_id,
+            )? {
+                Some(role) if role.can(Permission::ViewLog) => (),
+                Some(_) => return Ok(HttpResponse::Forbidden().finish()),
+                None => return Ok(HttpResponse::NotFound().finish()),
+            }
+        }
+        // The log of a deleted group ends with the users who may still read it
+        None => match audit_log_tree.scan_prefix(prefix).next_back() {
+            Some(res) => {
+                let entry: Entry = serde_json::from_slice(&res?.1)?;
+                match entry.change {
+                    Change::Deleted { admins } if admins.contains(&user_id) => (),
+                    _ => return Ok(HttpResponse::NotFound().finish()),
+                }
+            }
+            None => return Ok(HttpResponse::NotFound().finish()),
+        },
+    }
+    let entries = match params.before {
+        Some(before) => {
+            let mut end = prefix.to_vec();
+            end.extend_from_slice(&before.to_be_bytes());
+            audit_log_tree.range(prefix.to_vec()..end)
+        }
+        None => audit_log_tree.scan_prefix(prefix),
+    };
+    let entries = entries
+        .rev()
+        .take(params.limit.min(MAX_LIMIT))
+        .map(|res| -> Result<LogEntry, Error> {
+            let (k, v) = res?;
+            Ok(LogEntry {
+                entry_id: u64::from_be_bytes(k[8..16].try_into().unwrap()),
+                entry: serde_json::from_slice(&v)?,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{db, group, join, json, run, TestUser};
+
+    fn log(db: &web::Data<sled::Db>, group_id: u64, user: &TestUser) -> HttpResponse {
+        let params = serde_json::from_value(serde_json::json!({})).unwrap();
+        run(list(
+            user.session(),
+            db.clone(),
+            web::Path::from(group_id),
+            web::Query(params),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn admins_read_log_of_deleted_group() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let admin = TestUser::new(&db, "admin");
+        let member = TestUser::new(&db, "member");
+        let group_id = group(&db, &owner);
+        join(&db, group_id, &owner, &admin);
+        join(&db, group_id, &owner, &member);
+        let params = serde_json::from_value(serde_json::json!({
+            "group_id": group_id,
+            "user_id": admin.id,
+        }))
+        .unwrap();
+        run(crate::group::make_admin(
+            owner.session(),
+            db.clone(),
+            web::Json(params),
+        ))
+        .unwrap();
+        let params = serde_json::from_value(serde_json::json!({ "group_id": group_id })).unwrap();
+        let response = run(crate::group::remove(
+            owner.session(),
+            db.clone(),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert!(response.status().is_success());
+
+        for user in &[&owner, &admin] {
+            let response = log(&db, group_id, user);
+            assert!(response.status().is_success());
+            let entries: Vec<serde_json::Value> = json(&response);
+            assert!(entries[0]["change"]["Deleted"].is_object());
+        }
+        assert_eq!(log(&db, group_id, &member).status(), 404);
+    }
+}
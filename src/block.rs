@@ -1,15 +1,84 @@
 use serde::{Deserialize, Deserializer, Serialize};
 
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Level {
+    Tentative,
+    #[default]
+    Hard,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Visibility {
+    Private,
+    #[default]
+    Busy,
+    Details,
+}
+
 #[derive(Serialize, Clone, PartialEq, Eq)]
 pub struct Block {
     start: u64,
     end: u64,
+    level: Level,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    category: Option<String>,
+    visibility: Visibility,
 }
 
 impl Block {
+    pub fn new(start: u64, end: u64) -> Block {
+        Block {
+            start,
+            end,
+            level: Level::Hard,
+            label: None,
+            note: None,
+            category: None,
+            visibility: Visibility::Busy,
+        }
+    }
+
     pub fn intersects(&self, other: &Block) -> bool {
         self.start < other.end && self.end > other.start
     }
+
+    pub fn with_level(self, level: Level) -> Block {
+        Block { level, ..self }
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// The time of this block without its details.
+    pub fn interval(&self) -> Interval {
+        Interval {
+            start: self.start,
+            end: self.end,
+        }
+    }
+
+    pub fn same_time(&self, other: &Block) -> bool {
+        self.start == other.start && self.end == other.end
+    }
+
+    /// This block as other members of a group get to see it, if at all.
+    pub fn shared(&self) -> Option<Block> {
+        match self.visibility {
+            Visibility::Private => None,
+            Visibility::Busy => Some(Block {
+                label: None,
+                note: None,
+                category: None,
+                ..self.clone()
+            }),
+            Visibility::Details => Some(self.clone()),
+        }
+    }
 }
 
 impl<'de> Deserialize<'de> for Block {
@@ -21,15 +90,94 @@ impl<'de> Deserialize<'de> for Block {
         struct DBlock {
             start: u64,
             end: u64,
+            #[serde(default)]
+            level: Level,
+            label: Option<String>,
+            note: Option<String>,
+            category: Option<String>,
+            #[serde(default)]
+            visibility: Visibility,
         }
         let block: DBlock = Deserialize::deserialize(deserializer)?;
         if block.start < block.end {
             Ok(Block {
                 start: block.start,
                 end: block.end,
+                level: block.level,
+                label: block.label,
+                note: block.note,
+                category: block.category,
+                visibility: block.visibility,
             })
         } else {
             Err(serde::de::Error::custom("Block must have positive length"))
         }
     }
 }
+
+/// A plain time interval, used for activities and time windows.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    start: u64,
+    end: u64,
+}
+
+impl Interval {
+    pub fn new(start: u64, end: u64) -> Interval {
+        Interval { start, end }
+    }
+
+    pub fn intersects(&self, other: &Interval) -> bool {
+        self.start < other.end && self.end > other.start
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// The strongest level among the given blocks that intersect this
+    /// interval.
+    pub fn conflict<'a>(&self, blocks: impl IntoIterator<Item = &'a Block>) -> Option<Level> {
+        blocks
+            .into_iter()
+            .filter(|b| self.intersects(&b.interval()))
+            .map(|b| b.level)
+            .max()
+    }
+
+    /// This interval extended by `by` seconds on both ends.
+    pub fn expand(&self, by: u64) -> Interval {
+        Interval {
+            start: self.start.saturating_sub(by),
+            end: self.end.saturating_add(by),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Interval {
+    fn deserialize<D>(deserializer: D) -> Result<Interval, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct DInterval {
+            start: u64,
+            end: u64,
+        }
+        let interval: DInterval = Deserialize::deserialize(deserializer)?;
+        if interval.start < interval.end {
+            Ok(Interval {
+                start: interval.start,
+                end: interval.end,
+            })
+        } else {
+            Err(serde::de::Error::custom(
+                "Interval must have positive length",
+            ))
+        }
+    }
+}
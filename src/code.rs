@@ -0,0 +1,381 @@
+use crate::{
+    group::Group,
+    holiday::Calendar,
+    quota::Quotas,
+    role::{Permission, Role},
+    session::Session,
+    util::{abort_response, now, Abort, Error},
+};
+use actix_web::{web, HttpResponse};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionalTree},
+    Transactional,
+};
+use std::collections::HashMap;
+
+pub const CODES_TREE: &[u8] = b"codes";
+pub const CODES_GROUP_TREE: &[u8] = b"codes_group";
+
+/// A code anyone can use to join a group with the given role. `expires` is a
+/// point in time and `max_uses` limits how often the code can be used, both
+/// are optional.
+#[derive(Serialize, Deserialize)]
+pub struct InviteCode {
+    group_id: u64,
+    created_by: u64,
+    expires: Option<u64>,
+    max_uses: Option<u32>,
+    uses: u32,
+    #[serde(alias = "admin", deserialize_with = "crate::role::deserialize_role")]
+    role: Role,
+}
+
+impl InviteCode {
+    fn valid(&self) -> bool {
+        now() < self.expires.unwrap_or(u64::MAX) && self.uses < self.max_uses.unwrap_or(u32::MAX)
+    }
+}
+
+/// The invite codes of a group, since transactions can't scan.
+pub fn of_group(db: &sled::Db, group_id: u64) -> Result<Vec<String>, Error> {
+    let codes_group_tree = db.open_tree(CODES_GROUP_TREE)?;
+    codes_group_tree
+        .scan_prefix(group_id.to_be_bytes())
+        .keys()
+        .map(|k| Ok(String::from_utf8(k?[8..].to_vec()).unwrap()))
+        .collect()
+}
+
+/// Deletes the invite codes of a group returned by `of_group`.
+pub fn delete_all(
+    codes_tree: &TransactionalTree,
+    codes_group_tree: &TransactionalTree,
+    group_id: u64,
+    codes: &[String],
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    for code in codes {
+        let mut key = Vec::with_capacity(8 + code.len());
+        key.extend_from_slice(&group_id.to_be_bytes());
+        key.extend_from_slice(code.as_bytes());
+        codes_tree.remove(code.as_bytes())?;
+        codes_group_tree.remove(key)?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct NewCode {
+    group_id: u64,
+    expires: Option<u64>,
+    max_uses: Option<u32>,
+    #[serde(default)]
+    role: Role,
+}
+
+pub async fn create(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Json<NewCode>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    let codes_tree = db.open_tree(CODES_TREE)?;
+    let codes_group_tree = db.open_tree(CODES_GROUP_TREE)?;
+    let params = params.into_inner();
+    let code: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .map(char::from)
+        .collect();
+    let invite_code = serde_json::to_vec(&InviteCode {
+        group_id: params.group_id,
+        created_by: user_id,
+        expires: params.expires,
+        max_uses: params.max_uses,
+        uses: 0,
+        role: params.role,
+    })?;
+    let mut key = Vec::with_capacity(8 + code.len());
+    key.extend_from_slice(&params.group_id.to_be_bytes());
+    key.extend_from_slice(code.as_bytes());
+
+    let result = (
+        &groups_tree,
+        &group_members_tree,
+        &codes_tree,
+        &codes_group_tree,
+    )
+        .transaction(
+            |(groups_tree, group_members_tree, codes_tree, codes_group_tree)| {
+                let group = crate::group::bump_version(groups_tree, params.group_id)?;
+                match crate::group::role_in(
+                    groups_tree,
+                    group_members_tree,
+                    params.group_id,
+                    &group,
+                    user_id,
+                )? {
+                    Some(role)
+                        if role.can(Permission::ManageMembers)
+                            && role.can_grant(params.role)
+                            && !group.archived =>
+                    {
+                        codes_tree.insert(code.as_bytes(), invite_code.as_slice())?;
+                        codes_group_tree.insert(key.as_slice(), &[])?;
+                        Ok(())
+                    }
+                    Some(_) => sled::transaction::abort(Abort::NotAllowed),
+                    None => sled::transaction::abort(Abort::NotFound),
+                }
+            },
+        );
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().json(code)),
+        Err(err) => abort_response(err),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GroupParams {
+    group_id: u64,
+}
+
+pub async fn list(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Query<GroupParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    let codes_tree = db.open_tree(CODES_TREE)?;
+    let codes_group_tree = db.open_tree(CODES_GROUP_TREE)?;
+    let group = match groups_tree.get(params.group_id.to_be_bytes())? {
+        Some(group) => group,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let group: Group = serde_json::from_slice(&group)?;
+    match crate::group::role_in(
+        &groups_tree,
+        &group_members_tree,
+        params.group_id,
+        &group,
+        user_id,
+    )? {
+        Some(role) if role.can(Permission::ManageMembers) => (),
+        Some(_) => return Ok(HttpResponse::Forbidden().finish()),
+        None => return Ok(HttpResponse::NotFound().finish()),
+    }
+    let codes = codes_group_tree
+        .scan_prefix(params.group_id.to_be_bytes())
+        .map(|res| -> Result<(String, InviteCode), Error> {
+            let (k, _) = res?;
+            let invite_code = codes_tree.get(&k[8..])?.expect("Missing code");
+            let code = String::from_utf8(k[8..].to_vec()).unwrap();
+            Ok((code, serde_json::from_slice(&invite_code)?))
+        })
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    Ok(HttpResponse::Ok().json(codes))
+}
+
+#[derive(Deserialize)]
+pub struct CodeParams {
+    code: String,
+}
+
+pub async fn revoke(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Json<CodeParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    let codes_tree = db.open_tree(CODES_TREE)?;
+    let codes_group_tree = db.open_tree(CODES_GROUP_TREE)?;
+
+    let result = (
+        &groups_tree,
+        &group_members_tree,
+        &codes_tree,
+        &codes_group_tree,
+    )
+        .transaction(
+            |(groups_tree, group_members_tree, codes_tree, codes_group_tree)| {
+                let invite_code = codes_tree.get(params.code.as_bytes())?.ok_or(
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
+                )?;
+                let invite_code: InviteCode =
+                    serde_json::from_slice(&invite_code).map_err(|err| {
+                        sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(
+                            err,
+                        ))
+                    })?;
+                let group = groups_tree.get(invite_code.group_id.to_be_bytes())?.ok_or(
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
+                )?;
+                let group: Group = serde_json::from_slice(&group).map_err(|err| {
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(err))
+                })?;
+                match crate::group::role_in(
+                    groups_tree,
+                    group_members_tree,
+                    invite_code.group_id,
+                    &group,
+                    user_id,
+                )? {
+                    Some(role) if role.can(Permission::ManageMembers) => {
+                        let mut key = Vec::with_capacity(8 + params.code.len());
+                        key.extend_from_slice(&invite_code.group_id.to_be_bytes());
+                        key.extend_from_slice(params.code.as_bytes());
+                        codes_tree.remove(params.code.as_bytes())?;
+                        codes_group_tree.remove(key)?;
+                        Ok(())
+                    }
+                    Some(_) => sled::transaction::abort(Abort::NotAllowed),
+                    None => sled::transaction::abort(Abort::NotFound),
+                }
+            },
+        );
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => abort_response(err),
+    }
+}
+
+pub async fn join(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    calendar: web::Data<Calendar>,
+    quotas: web::Data<Quotas>,
+    params: web::Json<CodeParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let groups_user_tree = db.open_tree(crate::group::GROUPS_USER_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    let codes_tree = db.open_tree(CODES_TREE)?;
+    let activities_tree = db.open_tree(crate::activity::ACTIVITIES_TREE)?;
+    let activities_user_tree = db.open_tree(crate::activity::ACTIVITIES_USER_TREE)?;
+    let activities_conflict_tree = db.open_tree(crate::activity::ACTIVITIES_CONFLICT_TREE)?;
+    let activities_participants_tree =
+        db.open_tree(crate::activity::ACTIVITIES_PARTICIPANTS_TREE)?;
+    let users_tree = db.open_tree(crate::user::USERS_TREE)?;
+    let templates_tree = db.open_tree(crate::template::TEMPLATES_TREE)?;
+    let holidays_user_tree = db.open_tree(crate::holiday::HOLIDAYS_USER_TREE)?;
+    let audit_log_tree = db.open_tree(crate::audit::AUDIT_LOG_TREE)?;
+    // The group may change between reading it and the transaction
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        let (snapshot, upcoming) = match codes_tree.get(params.code.as_bytes())? {
+            Some(invite_code) => {
+                let invite_code: InviteCode = serde_json::from_slice(&invite_code)?;
+                (
+                    crate::group::snapshot(&db, invite_code.group_id)?,
+                    crate::activity::upcoming(&db, invite_code.group_id)?,
+                )
+            }
+            None => return Ok(HttpResponse::NotFound().finish()),
+        };
+
+        let result = (
+            &groups_tree,
+            &groups_user_tree,
+            &group_members_tree,
+            &codes_tree,
+            &activities_tree,
+            &activities_user_tree,
+            &activities_conflict_tree,
+            &activities_participants_tree,
+            &users_tree,
+            &templates_tree,
+            &holidays_user_tree,
+            &audit_log_tree,
+        )
+            .transaction(
+                |(
+                    groups_tree,
+                    groups_user_tree,
+                    group_members_tree,
+                    codes_tree,
+                    activities_tree,
+                    activities_user_tree,
+                    activities_conflict_tree,
+                    activities_participants_tree,
+                    users_tree,
+                    templates_tree,
+                    holidays_user_tree,
+                    audit_log_tree,
+                )| {
+                    let invite_code = codes_tree.get(params.code.as_bytes())?.ok_or(
+                        sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
+                    )?;
+                    let mut invite_code: InviteCode = serde_json::from_slice(&invite_code)
+                        .map_err(|err| {
+                            sled::transaction::ConflictableTransactionError::Abort(
+                                Abort::SerdeError(err),
+                            )
+                        })?;
+                    if !invite_code.valid() {
+                        sled::transaction::abort(Abort::NotFound)?;
+                    }
+                    // The upcoming activities have to be complete
+                    crate::group::check_snapshot(groups_tree, invite_code.group_id, &snapshot)?;
+                    if crate::group::add_member(
+                        groups_tree,
+                        groups_user_tree,
+                        group_members_tree,
+                        audit_log_tree,
+                        &quotas,
+                        invite_code.group_id,
+                        user_id,
+                        invite_code.role,
+                        user_id,
+                    )? {
+                        invite_code.uses += 1;
+                        let serialized = serde_json::to_vec(&invite_code).map_err(|err| {
+                            sled::transaction::ConflictableTransactionError::Abort(
+                                Abort::SerdeError(err),
+                            )
+                        })?;
+                        codes_tree.insert(params.code.as_bytes(), serialized)?;
+                        if invite_code.role.can(Permission::Participate) {
+                            let availability = crate::activity::availability(
+                                users_tree,
+                                templates_tree,
+                                holidays_user_tree,
+                                &calendar,
+                                user_id,
+                            )?;
+                            crate::activity::add_participant(
+                                activities_tree,
+                                activities_user_tree,
+                                activities_conflict_tree,
+                                activities_participants_tree,
+                                user_id,
+                                &upcoming,
+                                &availability,
+                            )?;
+                        }
+                    }
+                    Ok(invite_code.group_id)
+                },
+            );
+        if attempts == crate::group::ATTEMPTS
+            || !matches!(
+                result,
+                Err(sled::transaction::TransactionError::Abort(Abort::Stale))
+            )
+        {
+            break result;
+        }
+    };
+    match result {
+        Ok(group_id) => Ok(HttpResponse::Ok().json(group_id)),
+        Err(err) => abort_response(err),
+    }
+}
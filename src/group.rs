@@ -1,50 +1,586 @@
 use crate::{
+    audit::Change,
+    block::{Block, Interval, Level},
+    holiday::Calendar,
+    notification::Event,
+    quota::{Quota, Quotas},
+    role::{Permission, Role},
     session::Session,
-    util::{Abort, Error},
+    template::{Template, MAX_WINDOW},
+    user::User,
+    util::{abort_response, now, Abort, Conflicts, Error},
 };
 use actix_web::{web, HttpResponse};
 use serde::{Deserialize, Serialize};
-use serde_json::value::RawValue;
-use sled::Transactional;
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionalTree},
+    Transactional,
+};
 use std::{collections::HashMap, convert::TryInto};
 
 pub const GROUPS_TREE: &[u8] = b"groups";
-const GROUPS_USER_TREE: &[u8] = b"groups_user";
+pub const GROUPS_USER_TREE: &[u8] = b"groups_user";
+pub const GROUPS_CHILDREN_TREE: &[u8] = b"groups_children";
+pub const GROUP_MEMBERS_TREE: &[u8] = b"group_members";
 
+/// A group. Its members are stored in the group_members tree, with the
+/// groups_user tree as reverse index.
 #[derive(Serialize, Deserialize)]
 pub struct Group {
     pub name: String,
-    pub users: HashMap<u64, bool>, // TODO
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// URL of an image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+    /// Members of a subgroup have to be members of its parent group.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<u64>,
+    #[serde(default)]
+    pub settings: Settings,
+    /// Archived groups are read-only, they get no new activities, members or
+    /// answers to their activities.
+    #[serde(default)]
+    pub archived: bool,
+    /// Counts members joining and leaving as well as new subgroups,
+    /// activities and invites, so transactions notice when what they read
+    /// beforehand changed.
+    #[serde(default)]
+    pub version: u64,
+    /// Number of members, checked against the quota.
+    #[serde(default)]
+    pub member_count: u64,
+}
+
+/// Reads groups both inside and outside of transactions.
+pub trait Groups {
+    type Error;
+
+    fn group(&self, group_id: u64) -> Result<Option<Group>, Self::Error>;
+}
+
+impl Groups for sled::Tree {
+    type Error = Error;
+
+    fn group(&self, group_id: u64) -> Result<Option<Group>, Error> {
+        match self.get(group_id.to_be_bytes())? {
+            Some(group) => Ok(Some(serde_json::from_slice(&group)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Groups for TransactionalTree {
+    type Error = ConflictableTransactionError<Abort>;
+
+    fn group(&self, group_id: u64) -> Result<Option<Group>, Self::Error> {
+        match self.get(group_id.to_be_bytes())? {
+            Some(group) => Ok(Some(serde_json::from_slice(&group).map_err(|err| {
+                ConflictableTransactionError::Abort(Abort::SerdeError(err))
+            })?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Reads roles of members both inside and outside of transactions.
+pub trait Members {
+    type Error;
+
+    fn role(&self, group_id: u64, user_id: u64) -> Result<Option<Role>, Self::Error>;
+}
+
+impl Members for sled::Tree {
+    type Error = Error;
+
+    fn role(&self, group_id: u64, user_id: u64) -> Result<Option<Role>, Error> {
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&group_id.to_be_bytes());
+        key.extend_from_slice(&user_id.to_be_bytes());
+        match self.get(key)? {
+            Some(role) => Ok(Some(serde_json::from_slice(&role)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Members for TransactionalTree {
+    type Error = ConflictableTransactionError<Abort>;
+
+    fn role(&self, group_id: u64, user_id: u64) -> Result<Option<Role>, Self::Error> {
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&group_id.to_be_bytes());
+        key.extend_from_slice(&user_id.to_be_bytes());
+        match self.get(key)? {
+            Some(role) => Ok(Some(serde_json::from_slice(&role).map_err(|err| {
+                ConflictableTransactionError::Abort(Abort::SerdeError(err))
+            })?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Gives a user a role in a group, making the user a member if necessary.
+/// The groups_user tree has to be updated separately.
+fn store_role(
+    group_members_tree: &TransactionalTree,
+    group_id: u64,
+    user_id: u64,
+    role: Role,
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&group_id.to_be_bytes());
+    key.extend_from_slice(&user_id.to_be_bytes());
+    let role = serde_json::to_vec(&role)
+        .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+    group_members_tree.insert(key, role)?;
+    Ok(())
+}
+
+/// All members of a group with their roles, since transactions can't scan.
+pub fn members(db: &sled::Db, group_id: u64) -> Result<HashMap<u64, Role>, Error> {
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    group_members_tree
+        .scan_prefix(group_id.to_be_bytes())
+        .map(|res| -> Result<(u64, Role), Error> {
+            let (k, v) = res?;
+            let user_id = u64::from_be_bytes(k[8..16].try_into().unwrap());
+            Ok((user_id, serde_json::from_slice(&v)?))
+        })
+        .collect()
+}
+
+/// The members of a group read before a transaction, along with the version
+/// of the group they were read at. Taken before reading anything else about
+/// the group, so that later changes show up as a newer version.
+pub struct Snapshot {
+    version: u64,
+    pub members: HashMap<u64, Role>,
+}
+
+/// How often a handler reads a group again after it changed before the
+/// transaction, before giving up with CONFLICT.
+pub const ATTEMPTS: u32 = 3;
+
+/// Reads the members of a group for `current_members`.
+pub fn snapshot(db: &sled::Db, group_id: u64) -> Result<Snapshot, Error> {
+    let groups_tree = db.open_tree(GROUPS_TREE)?;
+    // Read first, so that changes while scanning show up as a newer version
+    let version = match groups_tree.group(group_id)? {
+        Some(group) => group.version,
+        None => 0,
+    };
+    Ok(Snapshot {
+        version,
+        members: members(db, group_id)?,
+    })
+}
+
+/// Aborts with `Abort::Stale` if the group changed since the snapshot, which
+/// handlers retry with a new snapshot.
+pub fn check_snapshot(
+    groups_tree: &TransactionalTree,
+    group_id: u64,
+    snapshot: &Snapshot,
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    match groups_tree.group(group_id)? {
+        Some(group) if group.version != snapshot.version => sled::transaction::abort(Abort::Stale),
+        Some(_) => Ok(()),
+        None => sled::transaction::abort(Abort::NotFound),
+    }
+}
+
+/// The current roles of the members of a snapshot, see `check_snapshot`.
+pub fn current_members(
+    groups_tree: &TransactionalTree,
+    group_members_tree: &TransactionalTree,
+    group_id: u64,
+    snapshot: &Snapshot,
+) -> Result<HashMap<u64, Role>, ConflictableTransactionError<Abort>> {
+    check_snapshot(groups_tree, group_id, snapshot)?;
+    let mut current = HashMap::with_capacity(snapshot.members.len());
+    for &user_id in snapshot.members.keys() {
+        if let Some(role) = group_members_tree.role(group_id, user_id)? {
+            current.insert(user_id, role);
+        }
+    }
+    Ok(current)
+}
+
+/// Counts a change to a group, see `Group::version`, and returns the group.
+pub fn bump_version(
+    groups_tree: &TransactionalTree,
+    group_id: u64,
+) -> Result<Group, ConflictableTransactionError<Abort>> {
+    let mut group = groups_tree
+        .group(group_id)?
+        .ok_or(ConflictableTransactionError::Abort(Abort::NotFound))?;
+    store_changed(groups_tree, group_id, &mut group)?;
+    Ok(group)
+}
+
+/// Stores a group after a change, counting it in `Group::version`.
+fn store_changed(
+    groups_tree: &TransactionalTree,
+    group_id: u64,
+    group: &mut Group,
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    group.version += 1;
+    let value = serde_json::to_vec(group)
+        .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+    groups_tree.insert(&group_id.to_be_bytes(), value)?;
+    Ok(())
+}
+
+/// The role of a user in a group. Admins and owners of a parent group manage
+/// its subgroups as admins, unless they have a higher role there.
+pub fn role_in<G: Groups, M: Members<Error = G::Error>>(
+    groups_tree: &G,
+    group_members_tree: &M,
+    group_id: u64,
+    group: &Group,
+    user_id: u64,
+) -> Result<Option<Role>, G::Error> {
+    let role = group_members_tree.role(group_id, user_id)?;
+    let mut parent_id = group.parent;
+    while let Some(id) = parent_id {
+        if group_members_tree.role(id, user_id)? >= Some(Role::Admin) {
+            return Ok(role.max(Some(Role::Admin)));
+        }
+        parent_id = match groups_tree.group(id)? {
+            Some(parent) => parent.parent,
+            None => break,
+        };
+    }
+    Ok(role)
+}
+
+/// Whether a user may join a group, i.e. is a member of its parent group if
+/// there is one.
+pub fn in_parent(
+    group_members_tree: &TransactionalTree,
+    group: &Group,
+    user_id: u64,
+) -> Result<bool, ConflictableTransactionError<Abort>> {
+    match group.parent {
+        Some(parent_id) => Ok(group_members_tree.role(parent_id, user_id)?.is_some()),
+        None => Ok(true),
+    }
+}
+
+/// The direct subgroups of a group.
+fn children(db: &sled::Db, group_id: u64) -> Result<Vec<u64>, Error> {
+    let groups_children_tree = db.open_tree(GROUPS_CHILDREN_TREE)?;
+    groups_children_tree
+        .scan_prefix(group_id.to_be_bytes())
+        .keys()
+        .map(|k| Ok(u64::from_be_bytes(k?[8..16].try_into().unwrap())))
+        .collect()
+}
+
+/// All subgroups below a group, since transactions can't scan.
+pub fn subgroups(db: &sled::Db, group_id: u64) -> Result<Vec<u64>, Error> {
+    let groups_children_tree = db.open_tree(GROUPS_CHILDREN_TREE)?;
+    let mut subgroups = Vec::new();
+    let mut queue = vec![group_id];
+    while let Some(id) = queue.pop() {
+        for res in groups_children_tree.scan_prefix(id.to_be_bytes()) {
+            let (k, _) = res?;
+            let child = u64::from_be_bytes(k[8..16].try_into().unwrap());
+            subgroups.push(child);
+            queue.push(child);
+        }
+    }
+    Ok(subgroups)
+}
+
+/// The invitations, invite codes and join requests of a group, which are
+/// deleted along with it.
+#[derive(Default)]
+pub struct Invites {
+    invitations: Vec<u64>,
+    codes: Vec<String>,
+    requests: Vec<u64>,
+}
+
+/// Collects the invites of a group, since transactions can't scan.
+pub fn invites(db: &sled::Db, group_id: u64) -> Result<Invites, Error> {
+    Ok(Invites {
+        invitations: crate::invitation::of_group(db, group_id)?,
+        codes: crate::code::of_group(db, group_id)?,
+        requests: crate::request::of_group(db, group_id)?,
+    })
+}
+
+/// What happens when the last owner or admin of a group leaves.
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub enum Succession {
+    /// Leaving is refused until someone else has been made admin.
+    #[default]
+    Refuse,
+    /// The longest-standing member becomes the owner.
+    Promote,
+    /// The group is deleted.
+    Delete,
+}
+
+/// Who may create activities for a group.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ActivityPolicy {
+    /// Everyone who may take part in activities.
+    #[default]
+    Members,
+    /// Organizers and higher ranks.
+    Organizers,
+    /// Everyone who may take part, but activities of members below admin
+    /// need to be approved first.
+    Approval,
+}
+
+impl ActivityPolicy {
+    pub fn allows(self, role: Role) -> bool {
+        match self {
+            ActivityPolicy::Members | ActivityPolicy::Approval => {
+                role.can(Permission::CreateActivity)
+            }
+            ActivityPolicy::Organizers => role >= Role::Organizer,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Settings {
+    #[serde(default)]
+    pub succession: Succession,
+    #[serde(default)]
+    pub activities: ActivityPolicy,
+    /// Public groups can be found by anyone, who may then ask to join.
+    #[serde(default)]
+    pub public: bool,
+    /// Used for new activities which don't give their own.
+    #[serde(default = "default_min_participants")]
+    pub min_participants: u32,
+    #[serde(default)]
+    pub max_participants: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            succession: Succession::default(),
+            activities: ActivityPolicy::default(),
+            public: false,
+            min_participants: default_min_participants(),
+            max_participants: 0,
+        }
+    }
+}
+
+fn default_min_participants() -> u32 {
+    1
+}
+
+/// Groups used to store their members along with the group.
+#[derive(Deserialize)]
+struct StoredMembers {
+    #[serde(default, deserialize_with = "crate::role::deserialize_roles")]
+    users: HashMap<u64, Role>,
+}
+
+/// Moves the members of groups stored along with the group into the
+/// group_members tree. Groups stored before roles existed get their admin with
+/// the lowest user id as owner.
+pub fn migrate(db: &sled::Db) -> Result<(), Error> {
+    let groups_tree = db.open_tree(GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    for res in groups_tree.iter() {
+        let (k, v) = res?;
+        let mut users = serde_json::from_slice::<StoredMembers>(&v)?.users;
+        if users.is_empty() {
+            continue;
+        }
+        if !users.values().any(|&role| role == Role::Owner) {
+            let owner = users
+                .iter()
+                .filter(|(_, &role)| role == Role::Admin)
+                .map(|(&user_id, _)| user_id)
+                .min();
+            if let Some(owner) = owner {
+                users.insert(owner, Role::Owner);
+            }
+        }
+        let mut batch = sled::Batch::default();
+        for (user_id, role) in users {
+            let mut key = Vec::with_capacity(16);
+            key.extend_from_slice(&k);
+            key.extend_from_slice(&user_id.to_be_bytes());
+            batch.insert(key, serde_json::to_vec(&role)?);
+        }
+        group_members_tree.apply_batch(batch)?;
+        let group: Group = serde_json::from_slice(&v)?;
+        groups_tree.insert(k, serde_json::to_vec(&group)?)?;
+    }
+    Ok(())
 }
 
 pub async fn create(
     session: web::Query<Session>,
     db: web::Data<sled::Db>,
+    quotas: web::Data<Quotas>,
     name: web::Json<String>,
 ) -> Result<HttpResponse, Error> {
     let user_id: u64 = session.get(&db)?;
     let groups_tree = db.open_tree(GROUPS_TREE)?;
     let groups_user_tree = db.open_tree(GROUPS_USER_TREE)?;
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    let audit_log_tree = db.open_tree(crate::audit::AUDIT_LOG_TREE)?;
+    let groups_owned_tree = db.open_tree(crate::quota::GROUPS_OWNED_TREE)?;
     let group_id = db.generate_id()?;
     let group = Group {
         name: name.into_inner(),
-        users: std::iter::once((user_id, true)).collect(),
+        description: None,
+        avatar: None,
+        parent: None,
+        settings: Settings::default(),
+        archived: false,
+        version: 0,
+        member_count: 1,
     };
     let group = serde_json::to_vec(&group)?;
-    (&groups_tree, &groups_user_tree)
-        .transaction(|(groups_tree, groups_user_tree)| {
-            groups_tree.insert(&group_id.to_be_bytes(), group.as_slice())?;
-            let mut key = Vec::with_capacity(16);
-            key.extend_from_slice(&user_id.to_be_bytes());
-            key.extend_from_slice(&group_id.to_be_bytes());
-            groups_user_tree.insert(key, &[])?;
-            Ok(())
-        })
-        .map_err(|err: sled::transaction::TransactionError<()>| match err {
-            sled::transaction::TransactionError::Storage(err) => err,
-            _ => unreachable!(),
-        })?;
-    Ok(HttpResponse::Ok().json(group_id))
+
+    let result = (
+        &groups_tree,
+        &groups_user_tree,
+        &group_members_tree,
+        &audit_log_tree,
+        &groups_owned_tree,
+    )
+        .transaction(
+            |(
+                groups_tree,
+                groups_user_tree,
+                group_members_tree,
+                audit_log_tree,
+                groups_owned_tree,
+            )| {
+                quotas.count_owned(groups_owned_tree, user_id)?;
+                groups_tree.insert(&group_id.to_be_bytes(), group.as_slice())?;
+                store_role(group_members_tree, group_id, user_id, Role::Owner)?;
+                let mut key = Vec::with_capacity(16);
+                key.extend_from_slice(&user_id.to_be_bytes());
+                key.extend_from_slice(&group_id.to_be_bytes());
+                groups_user_tree.insert(key, &now().to_be_bytes())?;
+                crate::audit::record(
+                    audit_log_tree,
+                    group_id,
+                    user_id,
+                    Change::MemberAdded {
+                        user_id,
+                        role: Role::Owner,
+                    },
+                )
+            },
+        );
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().json(group_id)),
+        Err(err) => abort_response(err),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SubgroupParams {
+    parent_id: u64,
+    name: String,
+}
+
+pub async fn create_subgroup(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    quotas: web::Data<Quotas>,
+    params: web::Json<SubgroupParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(GROUPS_TREE)?;
+    let groups_user_tree = db.open_tree(GROUPS_USER_TREE)?;
+    let groups_children_tree = db.open_tree(GROUPS_CHILDREN_TREE)?;
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    let audit_log_tree = db.open_tree(crate::audit::AUDIT_LOG_TREE)?;
+    let groups_owned_tree = db.open_tree(crate::quota::GROUPS_OWNED_TREE)?;
+    let group_id = db.generate_id()?;
+    let group = serde_json::to_vec(&Group {
+        name: params.name.clone(),
+        description: None,
+        avatar: None,
+        parent: Some(params.parent_id),
+        settings: Settings::default(),
+        archived: false,
+        version: 0,
+        member_count: 1,
+    })?;
+
+    let result = (
+        &groups_tree,
+        &groups_user_tree,
+        &groups_children_tree,
+        &group_members_tree,
+        &audit_log_tree,
+        &groups_owned_tree,
+    )
+        .transaction(
+            |(
+                groups_tree,
+                groups_user_tree,
+                groups_children_tree,
+                group_members_tree,
+                audit_log_tree,
+                groups_owned_tree,
+            )| {
+                let parent = bump_version(groups_tree, params.parent_id)?;
+                match role_in(
+                    groups_tree,
+                    group_members_tree,
+                    params.parent_id,
+                    &parent,
+                    user_id,
+                )? {
+                    Some(role) if role.can(Permission::ManageGroup) => (),
+                    Some(_) => sled::transaction::abort(Abort::NotAllowed)?,
+                    None => sled::transaction::abort(Abort::NotFound)?,
+                }
+                quotas.count_owned(groups_owned_tree, user_id)?;
+                groups_tree.insert(&group_id.to_be_bytes(), group.as_slice())?;
+                store_role(group_members_tree, group_id, user_id, Role::Owner)?;
+                let mut key = Vec::with_capacity(16);
+                key.extend_from_slice(&user_id.to_be_bytes());
+                key.extend_from_slice(&group_id.to_be_bytes());
+                groups_user_tree.insert(key, &now().to_be_bytes())?;
+                let mut key = Vec::with_capacity(16);
+                key.extend_from_slice(&params.parent_id.to_be_bytes());
+                key.extend_from_slice(&group_id.to_be_bytes());
+                groups_children_tree.insert(key, &[])?;
+                crate::audit::record(
+                    audit_log_tree,
+                    group_id,
+                    user_id,
+                    Change::MemberAdded {
+                        user_id,
+                        role: Role::Owner,
+                    },
+                )
+            },
+        );
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().json(group_id)),
+        Err(err) => abort_response(err),
+    }
+}
+
+/// A group along with its direct subgroups.
+#[derive(Serialize)]
+pub struct GroupInfo {
+    #[serde(flatten)]
+    group: Group,
+    subgroups: Vec<u64>,
 }
 
 pub async fn list(
@@ -56,23 +592,656 @@ pub async fn list(
     let groups_user_tree = db.open_tree(GROUPS_USER_TREE)?;
     let groups = groups_user_tree
         .scan_prefix(user_id.to_be_bytes())
-        .map(|res| -> Result<(u64, Box<RawValue>), Error> {
+        .map(|res| -> Result<(u64, GroupInfo), Error> {
             let (k, _) = res?;
             let group = groups_tree.get(&k[8..16])?.expect("Missing group_id");
             let group_id = u64::from_be_bytes(k[8..16].try_into().unwrap());
-            Ok((group_id, serde_json::from_slice(&group)?))
+            Ok((
+                group_id,
+                GroupInfo {
+                    group: serde_json::from_slice(&group)?,
+                    subgroups: children(&db, group_id)?,
+                },
+            ))
         })
         .collect::<Result<HashMap<_, _>, _>>()?;
     Ok(HttpResponse::Ok().json(groups))
 }
 
+#[derive(Serialize)]
+pub struct Member {
+    username: String,
+    role: Role,
+}
+
+#[derive(Serialize)]
+pub struct GroupDetails {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent: Option<u64>,
+    subgroups: Vec<u64>,
+    settings: Settings,
+    archived: bool,
+    members: HashMap<u64, Member>,
+    upcoming_activities: usize,
+}
+
+pub async fn get(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    group_id: web::Path<u64>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let group_id = group_id.into_inner();
+    let groups_tree = db.open_tree(GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    let users_tree = db.open_tree(crate::user::USERS_TREE)?;
+    let group = match groups_tree.get(group_id.to_be_bytes())? {
+        Some(group) => group,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let group: Group = serde_json::from_slice(&group)?;
+    if role_in(&groups_tree, &group_members_tree, group_id, &group, user_id)?.is_none() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    let members = members(&db, group_id)?
+        .into_iter()
+        .map(|(id, role)| -> Result<(u64, Member), Error> {
+            let user = users_tree.get(id.to_be_bytes())?.expect("Missing user_id");
+            let user: User = serde_json::from_slice(&user)?;
+            Ok((
+                id,
+                Member {
+                    username: user.username,
+                    role,
+                },
+            ))
+        })
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    Ok(HttpResponse::Ok().json(GroupDetails {
+        name: group.name,
+        description: group.description,
+        avatar: group.avatar,
+        parent: group.parent,
+        subgroups: children(&db, group_id)?,
+        settings: group.settings,
+        archived: group.archived,
+        members,
+        upcoming_activities: crate::activity::upcoming(&db, group_id)?.len(),
+    }))
+}
+
+/// Changes to the details of a group. Empty descriptions and avatars remove
+/// them.
+#[derive(Deserialize)]
+pub struct GroupChanges {
+    name: Option<String>,
+    description: Option<String>,
+    avatar: Option<String>,
+}
+
+pub async fn update(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    group_id: web::Path<u64>,
+    changes: web::Json<GroupChanges>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let group_id = group_id.into_inner();
+    let groups_tree = db.open_tree(GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    let audit_log_tree = db.open_tree(crate::audit::AUDIT_LOG_TREE)?;
+
+    let result = (&groups_tree, &group_members_tree, &audit_log_tree).transaction(
+        |(groups_tree, group_members_tree, audit_log_tree)| match groups_tree
+            .get(group_id.to_be_bytes())?
+        {
+            Some(group) => {
+                let mut group: Group = serde_json::from_slice(&group).map_err(|err| {
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(err))
+                })?;
+                match role_in(groups_tree, group_members_tree, group_id, &group, user_id)? {
+                    Some(role) if role.can(Permission::ManageGroup) => (),
+                    Some(_) => sled::transaction::abort(Abort::NotAllowed)?,
+                    None => sled::transaction::abort(Abort::NotFound)?,
+                }
+                match &changes.name {
+                    Some(name) if *name != group.name => {
+                        group.name = name.clone();
+                        crate::audit::record(
+                            audit_log_tree,
+                            group_id,
+                            user_id,
+                            Change::Renamed { name: name.clone() },
+                        )?;
+                    }
+                    _ => (),
+                }
+                if let Some(description) = &changes.description {
+                    group.description = Some(description.clone()).filter(|d| !d.is_empty());
+                }
+                if let Some(avatar) = &changes.avatar {
+                    group.avatar = Some(avatar.clone()).filter(|a| !a.is_empty());
+                }
+                let group = serde_json::to_vec(&group).map_err(|err| {
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(err))
+                })?;
+                groups_tree.insert(&group_id.to_be_bytes(), group)?;
+                Ok(())
+            }
+            None => sled::transaction::abort(Abort::NotFound),
+        },
+    );
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => abort_response(err),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    #[serde(default)]
+    query: String,
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent: Option<u64>,
+    members: usize,
+}
+
+/// Finds public groups whose name or description contains the query.
+pub async fn search(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Query<SearchParams>,
+) -> Result<HttpResponse, Error> {
+    let _user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    let query = params.query.to_lowercase();
+    let mut groups = HashMap::new();
+    for res in groups_tree.iter() {
+        let (k, v) = res?;
+        let group: Group = serde_json::from_slice(&v)?;
+        let matches = |text: &str| text.to_lowercase().contains(&query);
+        if group.settings.public
+            && (matches(&group.name) || group.description.as_deref().is_some_and(matches))
+        {
+            groups.insert(
+                u64::from_be_bytes(k.as_ref().try_into().unwrap()),
+                SearchResult {
+                    members: group_members_tree.scan_prefix(&k).count(),
+                    name: group.name,
+                    description: group.description,
+                    avatar: group.avatar,
+                    parent: group.parent,
+                },
+            );
+        }
+    }
+    Ok(HttpResponse::Ok().json(groups))
+}
+
+#[derive(Deserialize)]
+pub struct AvailabilityParams {
+    group_id: u64,
+    start: u64,
+    end: u64,
+}
+
+/// The blocked times of a member within a window. Tentative blocks are listed
+/// separately since the member may still be available.
+#[derive(Serialize)]
+struct MemberAvailability {
+    blocks: Vec<Block>,
+    tentative: Vec<Block>,
+}
+
+pub async fn availability(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    calendar: web::Data<Calendar>,
+    params: web::Query<AvailabilityParams>,
+) -> Result<HttpResponse, Error> {
+    if params.start >= params.end || params.end - params.start > MAX_WINDOW {
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+    let user_id: u64 = session.get(&db)?;
+    let users_tree = db.open_tree(crate::user::USERS_TREE)?;
+    let templates_tree = db.open_tree(crate::template::TEMPLATES_TREE)?;
+    let holidays_user_tree = db.open_tree(crate::holiday::HOLIDAYS_USER_TREE)?;
+    let members = members(&db, params.group_id)?;
+    if !members.contains_key(&user_id) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    let window = Interval::new(params.start, params.end);
+    let blocks = members
+        .keys()
+        .map(|&id| -> Result<(u64, MemberAvailability), Error> {
+            let user = users_tree.get(id.to_be_bytes())?.expect("Missing user_id");
+            let user: User = serde_json::from_slice(&user)?;
+            let template = Template::from_value(templates_tree.get(id.to_be_bytes())?)?;
+            let holidays = calendar.holidays(holidays_user_tree.get(id.to_be_bytes())?)?;
+            let (tentative, blocks) = user
+                .blocks
+                .into_iter()
+                .filter(|b| window.intersects(&b.interval()))
+                .filter_map(|b| if id == user_id { Some(b) } else { b.shared() })
+                .chain(template.blocks(&window, &holidays))
+                .partition(|b| b.level() == Level::Tentative);
+            Ok((id, MemberAvailability { blocks, tentative }))
+        })
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    Ok(HttpResponse::Ok().json(blocks))
+}
+
 #[derive(Deserialize)]
 pub struct GroupUserParams {
+    pub group_id: u64,
+    pub user_id: u64,
+}
+
+/// Adds a user to a group on behalf of `added_by`. Does nothing and returns
+/// false if the user is already a member.
+#[allow(clippy::too_many_arguments)]
+pub fn add_member(
+    groups_tree: &TransactionalTree,
+    groups_user_tree: &TransactionalTree,
+    group_members_tree: &TransactionalTree,
+    audit_log_tree: &TransactionalTree,
+    quotas: &Quotas,
     group_id: u64,
     user_id: u64,
+    role: Role,
+    added_by: u64,
+) -> Result<bool, ConflictableTransactionError<Abort>> {
+    if group_members_tree.role(group_id, user_id)?.is_some() {
+        return Ok(false);
+    }
+    let mut group = groups_tree
+        .group(group_id)?
+        .ok_or(ConflictableTransactionError::Abort(Abort::NotFound))?;
+    if group.archived || !in_parent(group_members_tree, &group, user_id)? {
+        return sled::transaction::abort(Abort::NotAllowed);
+    }
+    quotas.check(Quota::Members, group.member_count)?;
+    group.member_count += 1;
+    store_changed(groups_tree, group_id, &mut group)?;
+    store_role(group_members_tree, group_id, user_id, role)?;
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&user_id.to_be_bytes());
+    key.extend_from_slice(&group_id.to_be_bytes());
+    groups_user_tree.insert(key, &now().to_be_bytes())?;
+    crate::audit::record(
+        audit_log_tree,
+        group_id,
+        added_by,
+        Change::MemberAdded { user_id, role },
+    )?;
+    Ok(true)
+}
+
+/// Removes a member from a group on behalf of `removed_by` and updates
+/// `members`, the current members of the group. When the owner leaves, the
+/// admin who joined first takes over. If there is no admin left the settings
+/// of the group decide what happens. Returns whether the group has to be
+/// deleted, which is always the case once it has no members.
+#[allow(clippy::too_many_arguments)]
+pub fn remove_member(
+    groups_tree: &TransactionalTree,
+    groups_user_tree: &TransactionalTree,
+    group_members_tree: &TransactionalTree,
+    audit_log_tree: &TransactionalTree,
+    groups_owned_tree: &TransactionalTree,
+    group_id: u64,
+    members: &mut HashMap<u64, Role>,
+    user_id: u64,
+    removed_by: u64,
+) -> Result<bool, ConflictableTransactionError<Abort>> {
+    let role = members
+        .remove(&user_id)
+        .ok_or(ConflictableTransactionError::Abort(Abort::NotFound))?;
+    let mut group = groups_tree
+        .group(group_id)?
+        .ok_or(ConflictableTransactionError::Abort(Abort::NotFound))?;
+    group.member_count = group.member_count.saturating_sub(1);
+    store_changed(groups_tree, group_id, &mut group)?;
+    if role == Role::Owner {
+        crate::quota::change_owned(groups_owned_tree, user_id, false)?;
+    }
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&group_id.to_be_bytes());
+    key.extend_from_slice(&user_id.to_be_bytes());
+    group_members_tree.remove(key)?;
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&user_id.to_be_bytes());
+    key.extend_from_slice(&group_id.to_be_bytes());
+    groups_user_tree.remove(key)?;
+    crate::audit::record(
+        audit_log_tree,
+        group_id,
+        removed_by,
+        Change::MemberRemoved { user_id },
+    )?;
+    if members.is_empty() {
+        return Ok(true);
+    }
+    if role >= Role::Admin && !members.values().any(|&role| role == Role::Owner) {
+        let mut candidates: Vec<u64> = members
+            .iter()
+            .filter(|(_, &role)| role == Role::Admin)
+            .map(|(&user_id, _)| user_id)
+            .collect();
+        if candidates.is_empty() {
+            match group.settings.succession {
+                Succession::Refuse => {
+                    return sled::transaction::abort(Abort::Conflict(Conflicts {
+                        groups: vec![group_id],
+                        ..Conflicts::default()
+                    }))
+                }
+                Succession::Promote => candidates.extend(members.keys()),
+                Succession::Delete => return Ok(true),
+            }
+        }
+        let successor = longest_standing(groups_user_tree, group_id, &candidates)?;
+        members.insert(successor, Role::Owner);
+        store_role(group_members_tree, group_id, successor, Role::Owner)?;
+        crate::quota::change_owned(groups_owned_tree, successor, true)?;
+        crate::audit::record(
+            audit_log_tree,
+            group_id,
+            removed_by,
+            Change::RoleChanged {
+                user_id: successor,
+                role: Role::Owner,
+            },
+        )?;
+    }
+    Ok(false)
+}
+
+/// Returns the member who joined the group first. Memberships from before
+/// join times were recorded count as the oldest.
+fn longest_standing(
+    groups_user_tree: &TransactionalTree,
+    group_id: u64,
+    user_ids: &[u64],
+) -> Result<u64, ConflictableTransactionError<Abort>> {
+    let mut oldest = None;
+    for &user_id in user_ids {
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&user_id.to_be_bytes());
+        key.extend_from_slice(&group_id.to_be_bytes());
+        let joined = match groups_user_tree.get(key)? {
+            Some(v) if v.len() == 8 => u64::from_be_bytes(v.as_ref().try_into().unwrap()),
+            _ => 0,
+        };
+        if oldest.is_none_or(|oldest| (joined, user_id) < oldest) {
+            oldest = Some((joined, user_id));
+        }
+    }
+    Ok(oldest.expect("No members left").1)
+}
+
+/// Deletes a group along with the memberships of `members`, its remaining
+/// members, and notifies them. Its invitations, invite codes and join requests
+/// are deleted as well, its activities have to be deleted separately with
+/// `activity::delete_all`. Subgroups of the group become top-level groups.
+/// Its log is kept for the members who could read it and `deleted_by`.
+#[allow(clippy::too_many_arguments)]
+pub fn delete(
+    groups_tree: &TransactionalTree,
+    groups_user_tree: &TransactionalTree,
+    groups_children_tree: &TransactionalTree,
+    group_members_tree: &TransactionalTree,
+    notifications_tree: &TransactionalTree,
+    audit_log_tree: &TransactionalTree,
+    groups_owned_tree: &TransactionalTree,
+    invitations_tree: &TransactionalTree,
+    invitations_group_tree: &TransactionalTree,
+    codes_tree: &TransactionalTree,
+    codes_group_tree: &TransactionalTree,
+    join_requests_tree: &TransactionalTree,
+    group_id: u64,
+    group: Group,
+    members: &HashMap<u64, Role>,
+    subgroups: &[u64],
+    invites: &Invites,
+    deleted_by: u64,
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    crate::invitation::delete_all(
+        invitations_tree,
+        invitations_group_tree,
+        group_id,
+        &invites.invitations,
+    )?;
+    crate::code::delete_all(codes_tree, codes_group_tree, group_id, &invites.codes)?;
+    crate::request::delete_all(join_requests_tree, group_id, &invites.requests)?;
+    for &subgroup_id in subgroups {
+        let mut subgroup = match groups_tree.group(subgroup_id)? {
+            Some(subgroup) if subgroup.parent == Some(group_id) => subgroup,
+            _ => continue,
+        };
+        subgroup.parent = None;
+        let subgroup = serde_json::to_vec(&subgroup)
+            .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+        groups_tree.insert(&subgroup_id.to_be_bytes(), subgroup)?;
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&group_id.to_be_bytes());
+        key.extend_from_slice(&subgroup_id.to_be_bytes());
+        groups_children_tree.remove(key)?;
+    }
+    if let Some(parent_id) = group.parent {
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&parent_id.to_be_bytes());
+        key.extend_from_slice(&group_id.to_be_bytes());
+        groups_children_tree.remove(key)?;
+    }
+    for &user_id in members.keys() {
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&group_id.to_be_bytes());
+        key.extend_from_slice(&user_id.to_be_bytes());
+        group_members_tree.remove(key)?;
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&user_id.to_be_bytes());
+        key.extend_from_slice(&group_id.to_be_bytes());
+        groups_user_tree.remove(key)?;
+        if members[&user_id] == Role::Owner {
+            crate::quota::change_owned(groups_owned_tree, user_id, false)?;
+        }
+        crate::notification::notify(
+            notifications_tree,
+            user_id,
+            Event::GroupDeleted {
+                group_id,
+                name: group.name.clone(),
+            },
+        )?;
+    }
+    groups_tree.remove(&group_id.to_be_bytes())?;
+    crate::audit::record(
+        audit_log_tree,
+        group_id,
+        deleted_by,
+        Change::Deleted {
+            admins: log_readers(members, deleted_by),
+        },
+    )
+}
+
+/// The members who may read the log of a group, and `user_id`.
+fn log_readers(members: &HashMap<u64, Role>, user_id: u64) -> Vec<u64> {
+    let mut readers: Vec<u64> = members
+        .iter()
+        .filter(|(&member_id, role)| member_id != user_id && role.can(Permission::ViewLog))
+        .map(|(&member_id, _)| member_id)
+        .collect();
+    readers.push(user_id);
+    readers
+}
+
+#[derive(Deserialize)]
+pub struct GroupParams {
+    pub group_id: u64,
+}
+
+pub async fn remove(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Json<GroupParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(GROUPS_TREE)?;
+    let groups_user_tree = db.open_tree(GROUPS_USER_TREE)?;
+    let activities_tree = db.open_tree(crate::activity::ACTIVITIES_TREE)?;
+    let activities_user_tree = db.open_tree(crate::activity::ACTIVITIES_USER_TREE)?;
+    let activities_conflict_tree = db.open_tree(crate::activity::ACTIVITIES_CONFLICT_TREE)?;
+    let activities_participants_tree =
+        db.open_tree(crate::activity::ACTIVITIES_PARTICIPANTS_TREE)?;
+    let activities_group_tree = db.open_tree(crate::activity::ACTIVITIES_GROUP_TREE)?;
+    let notifications_tree = db.open_tree(crate::notification::NOTIFICATIONS_TREE)?;
+    let proposals_tree = db.open_tree(crate::proposal::PROPOSALS_TREE)?;
+    let groups_children_tree = db.open_tree(GROUPS_CHILDREN_TREE)?;
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    let audit_log_tree = db.open_tree(crate::audit::AUDIT_LOG_TREE)?;
+    let invitations_tree = db.open_tree(crate::invitation::INVITATIONS_TREE)?;
+    let invitations_group_tree = db.open_tree(crate::invitation::INVITATIONS_GROUP_TREE)?;
+    let codes_tree = db.open_tree(crate::code::CODES_TREE)?;
+    let codes_group_tree = db.open_tree(crate::code::CODES_GROUP_TREE)?;
+    let join_requests_tree = db.open_tree(crate::request::JOIN_REQUESTS_TREE)?;
+    let groups_owned_tree = db.open_tree(crate::quota::GROUPS_OWNED_TREE)?;
+    // The group may change between reading it and the transaction
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        let snapshot = snapshot(&db, params.group_id)?;
+        let activities = crate::activity::of_group(&db, params.group_id)?;
+        let subgroups = subgroups(&db, params.group_id)?;
+        let invites = invites(&db, params.group_id)?;
+
+        // More trees than fit into a tuple
+        let result = [
+            &groups_tree,
+            &groups_user_tree,
+            &activities_tree,
+            &activities_user_tree,
+            &activities_conflict_tree,
+            &activities_participants_tree,
+            &activities_group_tree,
+            &notifications_tree,
+            &proposals_tree,
+            &groups_children_tree,
+            &group_members_tree,
+            &audit_log_tree,
+            &groups_owned_tree,
+            &invitations_tree,
+            &invitations_group_tree,
+            &codes_tree,
+            &codes_group_tree,
+            &join_requests_tree,
+        ][..]
+            .transaction(|trees| {
+                let [
+                    groups_tree,
+                    groups_user_tree,
+                    activities_tree,
+                    activities_user_tree,
+                    activities_conflict_tree,
+                    activities_participants_tree,
+                    activities_group_tree,
+                    notifications_tree,
+                    proposals_tree,
+                    groups_children_tree,
+                    group_members_tree,
+                    audit_log_tree,
+                    groups_owned_tree,
+                    invitations_tree,
+                    invitations_group_tree,
+                    codes_tree,
+                    codes_group_tree,
+                    join_requests_tree,
+                ]: &[TransactionalTree; 18] = trees.as_slice().try_into().unwrap();
+                let group = groups_tree.get(params.group_id.to_be_bytes())?.ok_or(
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
+                )?;
+                let group: Group = serde_json::from_slice(&group).map_err(|err| {
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(err))
+                })?;
+                match role_in(
+                    groups_tree,
+                    group_members_tree,
+                    params.group_id,
+                    &group,
+                    user_id,
+                )? {
+                    Some(role) if role.can(Permission::DeleteGroup) => (),
+                    Some(_) => sled::transaction::abort(Abort::NotAllowed)?,
+                    None => sled::transaction::abort(Abort::NotFound)?,
+                }
+                let members =
+                    current_members(groups_tree, group_members_tree, params.group_id, &snapshot)?;
+                crate::activity::delete_all(
+                    activities_tree,
+                    activities_user_tree,
+                    activities_conflict_tree,
+                    activities_participants_tree,
+                    activities_group_tree,
+                    proposals_tree,
+                    audit_log_tree,
+                    params.group_id,
+                    &members,
+                    &activities,
+                    user_id,
+                )?;
+                delete(
+                    groups_tree,
+                    groups_user_tree,
+                    groups_children_tree,
+                    group_members_tree,
+                    notifications_tree,
+                    audit_log_tree,
+                    groups_owned_tree,
+                    invitations_tree,
+                    invitations_group_tree,
+                    codes_tree,
+                    codes_group_tree,
+                    join_requests_tree,
+                    params.group_id,
+                    group,
+                    &members,
+                    &subgroups,
+                    &invites,
+                    user_id,
+                )
+            });
+        if attempts == ATTEMPTS
+            || !matches!(
+                result,
+                Err(sled::transaction::TransactionError::Abort(Abort::Stale))
+            )
+        {
+            break result;
+        }
+    };
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => abort_response(err),
+    }
 }
 
-pub async fn add_user(
+pub async fn remove_user(
     session: web::Query<Session>,
     db: web::Data<sled::Db>,
     params: web::Json<GroupUserParams>,
@@ -80,32 +1249,569 @@ pub async fn add_user(
     let user_id: u64 = session.get(&db)?;
     let groups_tree = db.open_tree(GROUPS_TREE)?;
     let groups_user_tree = db.open_tree(GROUPS_USER_TREE)?;
+    let activities_tree = db.open_tree(crate::activity::ACTIVITIES_TREE)?;
+    let activities_user_tree = db.open_tree(crate::activity::ACTIVITIES_USER_TREE)?;
+    let activities_conflict_tree = db.open_tree(crate::activity::ACTIVITIES_CONFLICT_TREE)?;
+    let activities_participants_tree =
+        db.open_tree(crate::activity::ACTIVITIES_PARTICIPANTS_TREE)?;
+    let activities_group_tree = db.open_tree(crate::activity::ACTIVITIES_GROUP_TREE)?;
+    let notifications_tree = db.open_tree(crate::notification::NOTIFICATIONS_TREE)?;
+    let proposals_tree = db.open_tree(crate::proposal::PROPOSALS_TREE)?;
+    let groups_children_tree = db.open_tree(GROUPS_CHILDREN_TREE)?;
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    let audit_log_tree = db.open_tree(crate::audit::AUDIT_LOG_TREE)?;
+    let invitations_tree = db.open_tree(crate::invitation::INVITATIONS_TREE)?;
+    let invitations_group_tree = db.open_tree(crate::invitation::INVITATIONS_GROUP_TREE)?;
+    let codes_tree = db.open_tree(crate::code::CODES_TREE)?;
+    let codes_group_tree = db.open_tree(crate::code::CODES_GROUP_TREE)?;
+    let join_requests_tree = db.open_tree(crate::request::JOIN_REQUESTS_TREE)?;
+    let groups_owned_tree = db.open_tree(crate::quota::GROUPS_OWNED_TREE)?;
+    // The group may change between reading it and the transaction
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        // Members of a group are removed from its subgroups as well
+        let group_ids: Vec<u64> = std::iter::once(params.group_id)
+            .chain(subgroups(&db, params.group_id)?)
+            .collect();
+        // The members, activities and invites are needed in case a group gets
+        // deleted
+        let activities = group_ids
+            .iter()
+            .map(|&id| -> Result<_, Error> {
+                Ok((
+                    id,
+                    (
+                        snapshot(&db, id)?,
+                        crate::activity::of_group(&db, id)?,
+                        crate::activity::upcoming(&db, id)?,
+                        invites(&db, id)?,
+                    ),
+                ))
+            })
+            .collect::<Result<HashMap<_, _>, _>>()?;
+
+        // More trees than fit into a tuple
+        let result = [
+            &groups_tree,
+            &groups_user_tree,
+            &activities_tree,
+            &activities_user_tree,
+            &activities_conflict_tree,
+            &activities_participants_tree,
+            &activities_group_tree,
+            &notifications_tree,
+            &proposals_tree,
+            &groups_children_tree,
+            &group_members_tree,
+            &audit_log_tree,
+            &groups_owned_tree,
+            &invitations_tree,
+            &invitations_group_tree,
+            &codes_tree,
+            &codes_group_tree,
+            &join_requests_tree,
+        ][..]
+            .transaction(|trees| {
+                let [
+                    groups_tree,
+                    groups_user_tree,
+                    activities_tree,
+                    activities_user_tree,
+                    activities_conflict_tree,
+                    activities_participants_tree,
+                    activities_group_tree,
+                    notifications_tree,
+                    proposals_tree,
+                    groups_children_tree,
+                    group_members_tree,
+                    audit_log_tree,
+                    groups_owned_tree,
+                    invitations_tree,
+                    invitations_group_tree,
+                    codes_tree,
+                    codes_group_tree,
+                    join_requests_tree,
+                ]: &[TransactionalTree; 18] = trees.as_slice().try_into().unwrap();
+                let group = groups_tree.group(params.group_id)?.ok_or(
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
+                )?;
+                match (
+                    role_in(
+                        groups_tree,
+                        group_members_tree,
+                        params.group_id,
+                        &group,
+                        user_id,
+                    )?,
+                    group_members_tree.role(params.group_id, params.user_id)?,
+                ) {
+                    (Some(role), Some(other_role))
+                        if user_id != params.user_id
+                            && !(role.can(Permission::ManageMembers) && other_role < role) =>
+                    {
+                        sled::transaction::abort(Abort::NotAllowed)?
+                    }
+                    (Some(_), Some(_)) => (),
+                    _ => sled::transaction::abort(Abort::NotFound)?,
+                }
+                for &group_id in &group_ids {
+                    let role = match group_members_tree.role(group_id, params.user_id)? {
+                        Some(role) => role,
+                        None => continue,
+                    };
+                    let group = match groups_tree.group(group_id)? {
+                        Some(group) if group.archived && group_id == params.group_id => {
+                            sled::transaction::abort(Abort::NotAllowed)?
+                        }
+                        Some(group) => group,
+                        None => continue,
+                    };
+                    let (snapshot, activities, upcoming, invites) = &activities[&group_id];
+                    let mut members =
+                        current_members(groups_tree, group_members_tree, group_id, snapshot)?;
+                    members.insert(params.user_id, role);
+                    // Answers to activities of archived subgroups are frozen
+                    if !group.archived {
+                        crate::activity::remove_participant(
+                            activities_tree,
+                            activities_user_tree,
+                            activities_conflict_tree,
+                            activities_participants_tree,
+                            params.user_id,
+                            upcoming,
+                        )?;
+                    }
+                    if remove_member(
+                        groups_tree,
+                        groups_user_tree,
+                        group_members_tree,
+                        audit_log_tree,
+                        groups_owned_tree,
+                        group_id,
+                        &mut members,
+                        params.user_id,
+                        user_id,
+                    )? {
+                        crate::activity::delete_all(
+                            activities_tree,
+                            activities_user_tree,
+                            activities_conflict_tree,
+                            activities_participants_tree,
+                            activities_group_tree,
+                            proposals_tree,
+                            audit_log_tree,
+                            group_id,
+                            &members,
+                            activities,
+                            user_id,
+                        )?;
+                        delete(
+                            groups_tree,
+                            groups_user_tree,
+                            groups_children_tree,
+                            group_members_tree,
+                            notifications_tree,
+                            audit_log_tree,
+                            groups_owned_tree,
+                            invitations_tree,
+                            invitations_group_tree,
+                            codes_tree,
+                            codes_group_tree,
+                            join_requests_tree,
+                            group_id,
+                            group,
+                            &members,
+                            &group_ids,
+                            invites,
+                            user_id,
+                        )?;
+                    }
+                }
+                Ok(())
+            });
+        if attempts == ATTEMPTS
+            || !matches!(
+                result,
+                Err(sled::transaction::TransactionError::Abort(Abort::Stale))
+            )
+        {
+            break result;
+        }
+    };
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => abort_response(err),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MergeParams {
+    group_id: u64,
+    /// Deleted once its members and activities are part of `group_id`.
+    merged_group_id: u64,
+}
+
+/// Merges a group into another one. Members keep the higher of their roles,
+/// except that the owner of the merged group becomes an admin. Activities and
+/// proposals are moved along with the statuses of their participants, and
+/// every member is invited to the upcoming activities of both groups.
+/// Subgroups of the merged group become subgroups of the remaining one.
+pub async fn merge(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    calendar: web::Data<Calendar>,
+    quotas: web::Data<Quotas>,
+    params: web::Json<MergeParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(GROUPS_TREE)?;
+    let groups_user_tree = db.open_tree(GROUPS_USER_TREE)?;
+    let groups_children_tree = db.open_tree(GROUPS_CHILDREN_TREE)?;
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    let activities_tree = db.open_tree(crate::activity::ACTIVITIES_TREE)?;
+    let activities_user_tree = db.open_tree(crate::activity::ACTIVITIES_USER_TREE)?;
+    let activities_conflict_tree = db.open_tree(crate::activity::ACTIVITIES_CONFLICT_TREE)?;
+    let activities_participants_tree =
+        db.open_tree(crate::activity::ACTIVITIES_PARTICIPANTS_TREE)?;
+    let activities_group_tree = db.open_tree(crate::activity::ACTIVITIES_GROUP_TREE)?;
+    let proposals_tree = db.open_tree(crate::proposal::PROPOSALS_TREE)?;
+    let users_tree = db.open_tree(crate::user::USERS_TREE)?;
+    let templates_tree = db.open_tree(crate::template::TEMPLATES_TREE)?;
+    let holidays_user_tree = db.open_tree(crate::holiday::HOLIDAYS_USER_TREE)?;
+    let notifications_tree = db.open_tree(crate::notification::NOTIFICATIONS_TREE)?;
+    let audit_log_tree = db.open_tree(crate::audit::AUDIT_LOG_TREE)?;
+    let invitations_tree = db.open_tree(crate::invitation::INVITATIONS_TREE)?;
+    let invitations_group_tree = db.open_tree(crate::invitation::INVITATIONS_GROUP_TREE)?;
+    let codes_tree = db.open_tree(crate::code::CODES_TREE)?;
+    let codes_group_tree = db.open_tree(crate::code::CODES_GROUP_TREE)?;
+    let join_requests_tree = db.open_tree(crate::request::JOIN_REQUESTS_TREE)?;
+    let groups_owned_tree = db.open_tree(crate::quota::GROUPS_OWNED_TREE)?;
+    // The group may change between reading it and the transaction
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        let merged_snapshot = snapshot(&db, params.merged_group_id)?;
+        let snapshot = snapshot(&db, params.group_id)?;
+        let subgroups = subgroups(&db, params.merged_group_id)?;
+        // A group can't end up as its own subgroup
+        if params.group_id == params.merged_group_id || subgroups.contains(&params.group_id) {
+            return Ok(HttpResponse::BadRequest().finish());
+        }
+        let activities = crate::activity::of_group(&db, params.merged_group_id)?;
+        let upcoming: Vec<u64> = crate::activity::upcoming(&db, params.group_id)?
+            .into_iter()
+            .chain(crate::activity::upcoming(&db, params.merged_group_id)?)
+            .collect();
+        let invites = invites(&db, params.merged_group_id)?;
+
+        // More trees than fit into a tuple
+        let result = [
+            &groups_tree,
+            &groups_user_tree,
+            &groups_children_tree,
+            &group_members_tree,
+            &activities_tree,
+            &activities_user_tree,
+            &activities_conflict_tree,
+            &activities_participants_tree,
+            &activities_group_tree,
+            &proposals_tree,
+            &users_tree,
+            &templates_tree,
+            &holidays_user_tree,
+            &notifications_tree,
+            &audit_log_tree,
+            &groups_owned_tree,
+            &invitations_tree,
+            &invitations_group_tree,
+            &codes_tree,
+            &codes_group_tree,
+            &join_requests_tree,
+        ][..]
+            .transaction(|trees| {
+                let [
+                    groups_tree,
+                    groups_user_tree,
+                    groups_children_tree,
+                    group_members_tree,
+                    activities_tree,
+                    activities_user_tree,
+                    activities_conflict_tree,
+                    activities_participants_tree,
+                    activities_group_tree,
+                    proposals_tree,
+                    users_tree,
+                    templates_tree,
+                    holidays_user_tree,
+                    notifications_tree,
+                    audit_log_tree,
+                    groups_owned_tree,
+                    invitations_tree,
+                    invitations_group_tree,
+                    codes_tree,
+                    codes_group_tree,
+                    join_requests_tree,
+                ]: &[TransactionalTree; 21] = trees.as_slice().try_into().unwrap();
+                let group = groups_tree.group(params.group_id)?.ok_or(
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
+                )?;
+                let merged_group = groups_tree.group(params.merged_group_id)?.ok_or(
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
+                )?;
+                for (id, group) in &[
+                    (params.group_id, &group),
+                    (params.merged_group_id, &merged_group),
+                ] {
+                    match role_in(groups_tree, group_members_tree, *id, group, user_id)? {
+                        Some(role) if role.can(Permission::MergeGroup) && !group.archived => (),
+                        Some(_) => sled::transaction::abort(Abort::NotAllowed)?,
+                        None => sled::transaction::abort(Abort::NotFound)?,
+                    }
+                }
+                let mut members =
+                    current_members(groups_tree, group_members_tree, params.group_id, &snapshot)?;
+                let merged_members = current_members(
+                    groups_tree,
+                    group_members_tree,
+                    params.merged_group_id,
+                    &merged_snapshot,
+                )?;
+                for (&member_id, &merged_role) in &merged_members {
+                    if merged_role == Role::Owner {
+                        crate::quota::change_owned(groups_owned_tree, member_id, false)?;
+                    }
+                    // The remaining group keeps its owner
+                    let merged_role = merged_role.min(Role::Admin);
+                    let role = match members.get(&member_id) {
+                        Some(&role) if role >= merged_role => role,
+                        Some(_) => {
+                            store_role(
+                                group_members_tree,
+                                params.group_id,
+                                member_id,
+                                merged_role,
+                            )?;
+                            crate::audit::record(
+                                audit_log_tree,
+                                params.group_id,
+                                user_id,
+                                Change::RoleChanged {
+                                    user_id: member_id,
+                                    role: merged_role,
+                                },
+                            )?;
+                            merged_role
+                        }
+                        None => {
+                            add_member(
+                                groups_tree,
+                                groups_user_tree,
+                                group_members_tree,
+                                audit_log_tree,
+                                &quotas,
+                                params.group_id,
+                                member_id,
+                                merged_role,
+                                user_id,
+                            )?;
+                            merged_role
+                        }
+                    };
+                    members.insert(member_id, role);
+                    let mut key = Vec::with_capacity(16);
+                    key.extend_from_slice(&params.merged_group_id.to_be_bytes());
+                    key.extend_from_slice(&member_id.to_be_bytes());
+                    group_members_tree.remove(key)?;
+                    let mut key = Vec::with_capacity(16);
+                    key.extend_from_slice(&member_id.to_be_bytes());
+                    key.extend_from_slice(&params.merged_group_id.to_be_bytes());
+                    groups_user_tree.remove(key)?;
+                    crate::notification::notify(
+                        notifications_tree,
+                        member_id,
+                        Event::GroupMerged {
+                            group_id: params.merged_group_id,
+                            name: merged_group.name.clone(),
+                            merged_into: params.group_id,
+                        },
+                    )?;
+                }
+                crate::activity::move_all(
+                    activities_tree,
+                    activities_group_tree,
+                    proposals_tree,
+                    params.merged_group_id,
+                    params.group_id,
+                    &activities,
+                )?;
+                for (&member_id, role) in &members {
+                    if !role.can(Permission::Participate) {
+                        continue;
+                    }
+                    let availability = crate::activity::availability(
+                        users_tree,
+                        templates_tree,
+                        holidays_user_tree,
+                        &calendar,
+                        member_id,
+                    )?;
+                    crate::activity::add_participant(
+                        activities_tree,
+                        activities_user_tree,
+                        activities_conflict_tree,
+                        activities_participants_tree,
+                        member_id,
+                        &upcoming,
+                        &availability,
+                    )?;
+                }
+                for &subgroup_id in &subgroups {
+                    let mut subgroup = match groups_tree.group(subgroup_id)? {
+                        Some(subgroup) if subgroup.parent == Some(params.merged_group_id) => {
+                            subgroup
+                        }
+                        _ => continue,
+                    };
+                    subgroup.parent = Some(params.group_id);
+                    let subgroup = serde_json::to_vec(&subgroup).map_err(|err| {
+                        ConflictableTransactionError::Abort(Abort::SerdeError(err))
+                    })?;
+                    groups_tree.insert(&subgroup_id.to_be_bytes(), subgroup)?;
+                    let mut key = Vec::with_capacity(16);
+                    key.extend_from_slice(&params.merged_group_id.to_be_bytes());
+                    key.extend_from_slice(&subgroup_id.to_be_bytes());
+                    groups_children_tree.remove(key)?;
+                    let mut key = Vec::with_capacity(16);
+                    key.extend_from_slice(&params.group_id.to_be_bytes());
+                    key.extend_from_slice(&subgroup_id.to_be_bytes());
+                    groups_children_tree.insert(key, &[])?;
+                }
+                if let Some(parent_id) = merged_group.parent {
+                    let mut key = Vec::with_capacity(16);
+                    key.extend_from_slice(&parent_id.to_be_bytes());
+                    key.extend_from_slice(&params.merged_group_id.to_be_bytes());
+                    groups_children_tree.remove(key)?;
+                }
+                // Invites to the merged group are not carried over
+                crate::invitation::delete_all(
+                    invitations_tree,
+                    invitations_group_tree,
+                    params.merged_group_id,
+                    &invites.invitations,
+                )?;
+                crate::code::delete_all(
+                    codes_tree,
+                    codes_group_tree,
+                    params.merged_group_id,
+                    &invites.codes,
+                )?;
+                crate::request::delete_all(
+                    join_requests_tree,
+                    params.merged_group_id,
+                    &invites.requests,
+                )?;
+                groups_tree.remove(&params.merged_group_id.to_be_bytes())?;
+                crate::audit::record(
+                    audit_log_tree,
+                    params.merged_group_id,
+                    user_id,
+                    Change::Deleted {
+                        admins: log_readers(&merged_members, user_id),
+                    },
+                )?;
+                crate::audit::record(
+                    audit_log_tree,
+                    params.group_id,
+                    user_id,
+                    Change::Merged {
+                        group_id: params.merged_group_id,
+                        name: merged_group.name.clone(),
+                    },
+                )
+            });
+        if attempts == ATTEMPTS
+            || !matches!(
+                result,
+                Err(sled::transaction::TransactionError::Abort(Abort::Stale))
+            )
+        {
+            break result;
+        }
+    };
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => abort_response(err),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SettingsParams {
+    group_id: u64,
+    succession: Option<Succession>,
+    activities: Option<ActivityPolicy>,
+    public: Option<bool>,
+    min_participants: Option<u32>,
+    max_participants: Option<u32>,
+}
+
+pub async fn set_settings(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Json<SettingsParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    let params = params.into_inner();
 
     let result =
-        (&groups_tree, &groups_user_tree).transaction(|(groups_tree, groups_user_tree)| {
-            match groups_tree.get(&params.group_id.to_be_bytes())? {
+        (&groups_tree, &group_members_tree).transaction(|(groups_tree, group_members_tree)| {
+            match groups_tree.get(params.group_id.to_be_bytes())? {
                 Some(group) => {
                     let mut group: Group = serde_json::from_slice(&group).map_err(|err| {
                         sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(
                             err,
                         ))
                     })?;
-                    match group.users.get(&user_id) {
-                        Some(true) => {
-                            group.users.insert(params.user_id, false);
+                    match role_in(
+                        groups_tree,
+                        group_members_tree,
+                        params.group_id,
+                        &group,
+                        user_id,
+                    )? {
+                        Some(role) if role.can(Permission::ManageGroup) => {
+                            let settings = &mut group.settings;
+                            if let Some(succession) = params.succession {
+                                settings.succession = succession;
+                            }
+                            if let Some(activities) = params.activities {
+                                settings.activities = activities;
+                            }
+                            if let Some(public) = params.public {
+                                settings.public = public;
+                            }
+                            if let Some(min_participants) = params.min_participants {
+                                settings.min_participants = min_participants;
+                            }
+                            if let Some(max_participants) = params.max_participants {
+                                settings.max_participants = max_participants;
+                            }
+                            if settings.max_participants != 0
+                                && settings.max_participants < settings.min_participants
+                            {
+                                return Ok(false);
+                            }
                             let group = serde_json::to_vec(&group).map_err(|err| {
                                 sled::transaction::ConflictableTransactionError::Abort(
                                     Abort::SerdeError(err),
                                 )
                             })?;
                             groups_tree.insert(&params.group_id.to_be_bytes(), group)?;
-                            let mut key = Vec::with_capacity(16);
-                            key.extend_from_slice(&params.user_id.to_be_bytes());
-                            key.extend_from_slice(&params.group_id.to_be_bytes());
-                            groups_user_tree.insert(key, &[])?;
-                            Ok(())
+                            Ok(true)
                         }
-                        Some(false) => sled::transaction::abort(Abort::NotAllowed),
+                        Some(_) => sled::transaction::abort(Abort::NotAllowed),
                         None => sled::transaction::abort(Abort::NotFound),
                     }
                 }
@@ -113,71 +1819,123 @@ pub async fn add_user(
             }
         });
     match result {
-        Ok(()) => Ok(HttpResponse::Ok().finish()),
-        Err(sled::transaction::TransactionError::Storage(err)) => Err(Error::SledError(err)),
-        Err(sled::transaction::TransactionError::Abort(abort)) => match abort {
-            Abort::NotFound => Ok(HttpResponse::NotFound().finish()),
-            Abort::NotAllowed => Ok(HttpResponse::Forbidden().finish()),
-            Abort::SerdeError(err) => Err(Error::SerdeError(err)),
+        Ok(true) => Ok(HttpResponse::Ok().finish()),
+        Ok(false) => Ok(HttpResponse::BadRequest().finish()),
+        Err(err) => abort_response(err),
+    }
+}
+
+/// Archives a group or restores an archived one.
+fn set_archived(
+    db: &sled::Db,
+    user_id: u64,
+    group_id: u64,
+    archived: bool,
+) -> Result<HttpResponse, Error> {
+    let groups_tree = db.open_tree(GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    let audit_log_tree = db.open_tree(crate::audit::AUDIT_LOG_TREE)?;
+
+    let result = (&groups_tree, &group_members_tree, &audit_log_tree).transaction(
+        |(groups_tree, group_members_tree, audit_log_tree)| match groups_tree.group(group_id)? {
+            Some(mut group) => {
+                match role_in(groups_tree, group_members_tree, group_id, &group, user_id)? {
+                    Some(role) if role.can(Permission::ManageGroup) => (),
+                    Some(_) => sled::transaction::abort(Abort::NotAllowed)?,
+                    None => sled::transaction::abort(Abort::NotFound)?,
+                }
+                if group.archived == archived {
+                    return Ok(());
+                }
+                group.archived = archived;
+                let group = serde_json::to_vec(&group)
+                    .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+                groups_tree.insert(&group_id.to_be_bytes(), group)?;
+                crate::audit::record(
+                    audit_log_tree,
+                    group_id,
+                    user_id,
+                    if archived {
+                        Change::Archived
+                    } else {
+                        Change::Unarchived
+                    },
+                )
+            }
+            None => sled::transaction::abort(Abort::NotFound),
         },
+    );
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => abort_response(err),
     }
 }
 
-pub async fn remove_user(
+pub async fn archive(
     session: web::Query<Session>,
     db: web::Data<sled::Db>,
-    params: web::Json<GroupUserParams>,
+    params: web::Json<GroupParams>,
 ) -> Result<HttpResponse, Error> {
     let user_id: u64 = session.get(&db)?;
+    set_archived(&db, user_id, params.group_id, true)
+}
+
+pub async fn unarchive(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Json<GroupParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    set_archived(&db, user_id, params.group_id, false)
+}
+
+/// Gives a member of a group a new role. Members may lower their own role,
+/// except for the owner who has to transfer ownership instead.
+fn change_role(
+    db: &sled::Db,
+    user_id: u64,
+    group_id: u64,
+    member_id: u64,
+    new_role: Role,
+) -> Result<HttpResponse, Error> {
     let groups_tree = db.open_tree(GROUPS_TREE)?;
-    let groups_user_tree = db.open_tree(GROUPS_USER_TREE)?;
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    let audit_log_tree = db.open_tree(crate::audit::AUDIT_LOG_TREE)?;
 
-    let result =
-        (&groups_tree, &groups_user_tree).transaction(|(groups_tree, groups_user_tree)| {
-            match groups_tree.get(&params.group_id.to_be_bytes())? {
-                Some(group) => {
-                    let mut group: Group = serde_json::from_slice(&group).map_err(|err| {
-                        sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(
-                            err,
-                        ))
-                    })?;
-                    match group.users.get(&user_id) {
-                        Some(admin) if *admin || user_id == params.user_id => {
-                            match group.users.remove(&params.user_id) {
-                                None => sled::transaction::abort(Abort::NotFound),
-                                Some(other_admin) if other_admin && user_id != params.user_id => {
-                                    sled::transaction::abort(Abort::NotAllowed)
-                                }
-                                Some(_) => {
-                                    let group = serde_json::to_vec(&group).map_err(|err| {
-                                        sled::transaction::ConflictableTransactionError::Abort(
-                                            Abort::SerdeError(err),
-                                        )
-                                    })?;
-                                    groups_tree.insert(&params.group_id.to_be_bytes(), group)?;
-                                    let mut key = Vec::with_capacity(16);
-                                    key.extend_from_slice(&params.user_id.to_be_bytes());
-                                    key.extend_from_slice(&params.group_id.to_be_bytes());
-                                    groups_user_tree.remove(key)?;
-                                    Ok(())
-                                }
-                            }
-                        }
-                        Some(_) => sled::transaction::abort(Abort::NotAllowed),
-                        None => sled::transaction::abort(Abort::NotFound),
-                    }
+    let result = (&groups_tree, &group_members_tree, &audit_log_tree).transaction(
+        |(groups_tree, group_members_tree, audit_log_tree)| match groups_tree.group(group_id)? {
+            Some(group) if group.archived => sled::transaction::abort(Abort::NotAllowed),
+            Some(group) => {
+                let role =
+                    match role_in(groups_tree, group_members_tree, group_id, &group, user_id)? {
+                        Some(role) => role,
+                        None => return sled::transaction::abort(Abort::NotFound),
+                    };
+                let allowed = match group_members_tree.role(group_id, member_id)? {
+                    None => return sled::transaction::abort(Abort::NotFound),
+                    Some(_) if member_id == user_id => new_role <= role && role != Role::Owner,
+                    Some(current) => role.can_assign(current, new_role),
+                };
+                if !allowed {
+                    return sled::transaction::abort(Abort::NotAllowed);
                 }
-                None => sled::transaction::abort(Abort::NotFound),
+                store_role(group_members_tree, group_id, member_id, new_role)?;
+                crate::audit::record(
+                    audit_log_tree,
+                    group_id,
+                    user_id,
+                    Change::RoleChanged {
+                        user_id: member_id,
+                        role: new_role,
+                    },
+                )
             }
-        });
+            None => sled::transaction::abort(Abort::NotFound),
+        },
+    );
     match result {
         Ok(()) => Ok(HttpResponse::Ok().finish()),
-        Err(sled::transaction::TransactionError::Storage(err)) => Err(Error::SledError(err)),
-        Err(sled::transaction::TransactionError::Abort(abort)) => match abort {
-            Abort::NotFound => Ok(HttpResponse::NotFound().finish()),
-            Abort::NotAllowed => Ok(HttpResponse::Forbidden().finish()),
-            Abort::SerdeError(err) => Err(Error::SerdeError(err)),
-        },
+        Err(err) => abort_response(err),
     }
 }
 
@@ -185,43 +1943,711 @@ pub async fn make_admin(
     session: web::Query<Session>,
     db: web::Data<sled::Db>,
     params: web::Json<GroupUserParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    change_role(&db, user_id, params.group_id, params.user_id, Role::Admin)
+}
+
+pub async fn demote(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Json<GroupUserParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    change_role(&db, user_id, params.group_id, params.user_id, Role::Member)
+}
+
+#[derive(Deserialize)]
+pub struct RoleParams {
+    group_id: u64,
+    user_id: u64,
+    role: Role,
+}
+
+pub async fn set_role(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Json<RoleParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    change_role(&db, user_id, params.group_id, params.user_id, params.role)
+}
+
+pub async fn transfer_ownership(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    quotas: web::Data<Quotas>,
+    params: web::Json<GroupUserParams>,
 ) -> Result<HttpResponse, Error> {
     let user_id: u64 = session.get(&db)?;
     let groups_tree = db.open_tree(GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    let audit_log_tree = db.open_tree(crate::audit::AUDIT_LOG_TREE)?;
+    let groups_owned_tree = db.open_tree(crate::quota::GROUPS_OWNED_TREE)?;
 
-    let result = groups_tree.transaction(|groups_tree| {
-        match groups_tree.get(&params.group_id.to_be_bytes())? {
-            Some(group) => {
-                let mut group: Group = serde_json::from_slice(&group).map_err(|err| {
-                    sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(err))
-                })?;
-                match group.users.get(&user_id) {
-                    Some(true) => match group.users.insert(params.user_id, true) {
-                        None => sled::transaction::abort(Abort::NotFound),
-                        Some(_) => {
-                            let group = serde_json::to_vec(&group).map_err(|err| {
-                                sled::transaction::ConflictableTransactionError::Abort(
-                                    Abort::SerdeError(err),
-                                )
-                            })?;
-                            groups_tree.insert(&params.group_id.to_be_bytes(), group)?;
-                            Ok(())
+    let result = (
+        &groups_tree,
+        &group_members_tree,
+        &audit_log_tree,
+        &groups_owned_tree,
+    )
+        .transaction(
+            |(groups_tree, group_members_tree, audit_log_tree, groups_owned_tree)| {
+                match group_members_tree.role(params.group_id, user_id)? {
+                    Some(role) if role.can(Permission::TransferOwnership) => {
+                        if groups_tree
+                            .group(params.group_id)?
+                            .is_some_and(|group| group.archived)
+                        {
+                            return sled::transaction::abort(Abort::NotAllowed);
                         }
-                    },
-                    Some(false) => sled::transaction::abort(Abort::NotAllowed),
+                        if group_members_tree
+                            .role(params.group_id, params.user_id)?
+                            .is_none()
+                        {
+                            return sled::transaction::abort(Abort::NotFound);
+                        }
+                        store_role(group_members_tree, params.group_id, user_id, Role::Admin)?;
+                        store_role(
+                            group_members_tree,
+                            params.group_id,
+                            params.user_id,
+                            Role::Owner,
+                        )?;
+                        crate::quota::change_owned(groups_owned_tree, user_id, false)?;
+                        quotas.count_owned(groups_owned_tree, params.user_id)?;
+                        crate::audit::record(
+                            audit_log_tree,
+                            params.group_id,
+                            user_id,
+                            Change::RoleChanged {
+                                user_id,
+                                role: Role::Admin,
+                            },
+                        )?;
+                        crate::audit::record(
+                            audit_log_tree,
+                            params.group_id,
+                            user_id,
+                            Change::RoleChanged {
+                                user_id: params.user_id,
+                                role: Role::Owner,
+                            },
+                        )
+                    }
+                    Some(_) => sled::transaction::abort(Abort::NotAllowed),
                     None => sled::transaction::abort(Abort::NotFound),
                 }
-            }
-            None => sled::transaction::abort(Abort::NotFound),
-        }
-    });
+            },
+        );
     match result {
         Ok(()) => Ok(HttpResponse::Ok().finish()),
-        Err(sled::transaction::TransactionError::Storage(err)) => Err(Error::SledError(err)),
-        Err(sled::transaction::TransactionError::Abort(abort)) => match abort {
-            Abort::NotFound => Ok(HttpResponse::NotFound().finish()),
-            Abort::NotAllowed => Ok(HttpResponse::Forbidden().finish()),
-            Abort::SerdeError(err) => Err(Error::SerdeError(err)),
-        },
+        Err(err) => abort_response(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{db, group, join, json, run, TestUser};
+
+    fn leave(db: &web::Data<sled::Db>, group_id: u64, user: &TestUser) -> HttpResponse {
+        let params = GroupUserParams {
+            group_id,
+            user_id: user.id,
+        };
+        run(remove_user(user.session(), db.clone(), web::Json(params))).unwrap()
+    }
+
+    fn set_succession(db: &web::Data<sled::Db>, group_id: u64, owner: &TestUser, to: &str) {
+        let params = serde_json::from_value(serde_json::json!({
+            "group_id": group_id,
+            "succession": to,
+        }))
+        .unwrap();
+        let response = run(set_settings(owner.session(), db.clone(), web::Json(params))).unwrap();
+        assert!(response.status().is_success());
+    }
+
+    fn subgroup(db: &web::Data<sled::Db>, parent_id: u64, by: &TestUser) -> u64 {
+        let params = SubgroupParams {
+            parent_id,
+            name: "Subgroup".to_string(),
+        };
+        let response = run(create_subgroup(
+            by.session(),
+            db.clone(),
+            crate::test_util::quotas(),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert_eq!(response.status(), 200);
+        json(&response)
+    }
+
+    #[test]
+    fn admin_takes_over_from_owner() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let member = TestUser::new(&db, "member");
+        let admin = TestUser::new(&db, "admin");
+        let group_id = group(&db, &owner);
+        join(&db, group_id, &owner, &member);
+        join(&db, group_id, &owner, &admin);
+        let params = GroupUserParams {
+            group_id,
+            user_id: admin.id,
+        };
+        run(make_admin(owner.session(), db.clone(), web::Json(params))).unwrap();
+
+        assert!(leave(&db, group_id, &owner).status().is_success());
+        let members = members(&db, group_id).unwrap();
+        assert_eq!(members.get(&admin.id), Some(&Role::Owner));
+        assert_eq!(members.get(&member.id), Some(&Role::Member));
+        assert!(!members.contains_key(&owner.id));
+    }
+
+    #[test]
+    fn refuse_keeps_last_admin() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let member = TestUser::new(&db, "member");
+        let group_id = group(&db, &owner);
+        join(&db, group_id, &owner, &member);
+
+        let response = leave(&db, group_id, &owner);
+        assert_eq!(response.status(), actix_web::http::StatusCode::CONFLICT);
+        assert_eq!(
+            members(&db, group_id).unwrap().get(&owner.id),
+            Some(&Role::Owner)
+        );
+    }
+
+    #[test]
+    fn promote_longest_standing_member() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let first = TestUser::new(&db, "first");
+        let second = TestUser::new(&db, "second");
+        let group_id = group(&db, &owner);
+        join(&db, group_id, &owner, &second);
+        join(&db, group_id, &owner, &first);
+        // Joined a day earlier than `second`
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&first.id.to_be_bytes());
+        key.extend_from_slice(&group_id.to_be_bytes());
+        db.open_tree(GROUPS_USER_TREE)
+            .unwrap()
+            .insert(key, &(now() - 86400).to_be_bytes())
+            .unwrap();
+        set_succession(&db, group_id, &owner, "Promote");
+
+        assert!(leave(&db, group_id, &owner).status().is_success());
+        let members = members(&db, group_id).unwrap();
+        assert_eq!(members.get(&first.id), Some(&Role::Owner));
+        assert_eq!(members.get(&second.id), Some(&Role::Member));
+    }
+
+    #[test]
+    fn delete_group_without_admins() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let member = TestUser::new(&db, "member");
+        let group_id = group(&db, &owner);
+        join(&db, group_id, &owner, &member);
+        set_succession(&db, group_id, &owner, "Delete");
+
+        assert!(leave(&db, group_id, &owner).status().is_success());
+        let groups_tree = db.open_tree(GROUPS_TREE).unwrap();
+        assert!(groups_tree.group(group_id).unwrap().is_none());
+        assert!(members(&db, group_id).unwrap().is_empty());
+        let groups_user_tree = db.open_tree(GROUPS_USER_TREE).unwrap();
+        assert!(groups_user_tree
+            .scan_prefix(member.id.to_be_bytes())
+            .next()
+            .is_none());
+    }
+
+    #[test]
+    fn report_subgroup_refusing_removal() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let admin = TestUser::new(&db, "admin");
+        let member = TestUser::new(&db, "member");
+        let group_id = group(&db, &owner);
+        join(&db, group_id, &owner, &admin);
+        join(&db, group_id, &owner, &member);
+        let params = GroupUserParams {
+            group_id,
+            user_id: admin.id,
+        };
+        run(make_admin(owner.session(), db.clone(), web::Json(params))).unwrap();
+        let params = SubgroupParams {
+            parent_id: group_id,
+            name: "Subgroup".to_string(),
+        };
+        let response = run(create_subgroup(
+            admin.session(),
+            db.clone(),
+            crate::test_util::quotas(),
+            web::Json(params),
+        ))
+        .unwrap();
+        let subgroup_id: u64 = json(&response);
+        join(&db, subgroup_id, &admin, &member);
+
+        let params = GroupUserParams {
+            group_id,
+            user_id: admin.id,
+        };
+        let response = run(remove_user(owner.session(), db.clone(), web::Json(params))).unwrap();
+        assert_eq!(response.status(), actix_web::http::StatusCode::CONFLICT);
+        let conflicts: serde_json::Value = json(&response);
+        assert_eq!(conflicts["groups"], serde_json::json!([subgroup_id]));
+    }
+
+    #[test]
+    fn parent_admin_creates_subgroup_activity() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let group_id = group(&db, &owner);
+        let params = SubgroupParams {
+            parent_id: group_id,
+            name: "Subgroup".to_string(),
+        };
+        let response = run(create_subgroup(
+            owner.session(),
+            db.clone(),
+            crate::test_util::quotas(),
+            web::Json(params),
+        ))
+        .unwrap();
+        let subgroup_id: u64 = json(&response);
+        let member = TestUser::new(&db, "member");
+        join(&db, group_id, &owner, &member);
+        join(&db, subgroup_id, &owner, &member);
+        set_succession(&db, subgroup_id, &owner, "Promote");
+        let response = leave(&db, subgroup_id, &owner);
+        assert!(response.status().is_success());
+
+        let params = serde_json::from_value(serde_json::json!({
+            "group_id": subgroup_id,
+            "block": {"start": now() + 86400, "end": now() + 90000},
+            "description": "Activity",
+        }))
+        .unwrap();
+        let response = run(crate::activity::create(
+            owner.session(),
+            db.clone(),
+            crate::test_util::calendar(),
+            crate::test_util::quotas(),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert!(response.status().is_success());
+    }
+
+    #[test]
+    fn delete_group_with_invites_and_activities() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let member = TestUser::new(&db, "member");
+        let invited = TestUser::new(&db, "invited");
+        let requesting = TestUser::new(&db, "requesting");
+        let group_id = group(&db, &owner);
+        join(&db, group_id, &owner, &member);
+        let params = GroupUserParams {
+            group_id,
+            user_id: invited.id,
+        };
+        run(crate::invitation::create(
+            owner.session(),
+            db.clone(),
+            web::Json(params),
+        ))
+        .unwrap();
+        let params = serde_json::from_value(serde_json::json!({ "group_id": group_id })).unwrap();
+        run(crate::code::create(
+            owner.session(),
+            db.clone(),
+            web::Json(params),
+        ))
+        .unwrap();
+        let params = serde_json::from_value(serde_json::json!({
+            "group_id": group_id,
+            "public": true,
+        }))
+        .unwrap();
+        run(set_settings(owner.session(), db.clone(), web::Json(params))).unwrap();
+        let params = serde_json::from_value(serde_json::json!({ "group_id": group_id })).unwrap();
+        run(crate::request::create(
+            requesting.session(),
+            db.clone(),
+            web::Json(params),
+        ))
+        .unwrap();
+        let params = serde_json::from_value(serde_json::json!({
+            "group_id": group_id,
+            "block": {"start": now() + 86400, "end": now() + 90000},
+            "description": "Activity",
+        }))
+        .unwrap();
+        let response = run(crate::activity::create(
+            owner.session(),
+            db.clone(),
+            crate::test_util::calendar(),
+            crate::test_util::quotas(),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert!(response.status().is_success());
+        let before = invites(&db, group_id).unwrap();
+        assert_eq!(before.invitations, vec![invited.id]);
+        assert_eq!(before.codes.len(), 1);
+        assert_eq!(before.requests, vec![requesting.id]);
+
+        let params = GroupParams { group_id };
+        let response = run(remove(owner.session(), db.clone(), web::Json(params))).unwrap();
+        assert!(response.status().is_success());
+        let after = invites(&db, group_id).unwrap();
+        assert!(after.invitations.is_empty());
+        assert!(after.codes.is_empty());
+        assert!(after.requests.is_empty());
+        for tree in &[
+            crate::invitation::INVITATIONS_TREE,
+            crate::code::CODES_TREE,
+            crate::activity::ACTIVITIES_USER_TREE,
+            crate::activity::ACTIVITIES_PARTICIPANTS_TREE,
+        ] {
+            assert!(db.open_tree(tree).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn delete_group_when_last_member_leaves() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let group_id = group(&db, &owner);
+
+        assert!(leave(&db, group_id, &owner).status().is_success());
+        let groups_tree = db.open_tree(GROUPS_TREE).unwrap();
+        assert!(groups_tree.group(group_id).unwrap().is_none());
+    }
+
+    #[test]
+    fn snapshot_is_stale_after_join() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let member = TestUser::new(&db, "member");
+        let group_id = group(&db, &owner);
+        let snapshot = snapshot(&db, group_id).unwrap();
+        join(&db, group_id, &owner, &member);
+
+        let groups_tree = db.open_tree(GROUPS_TREE).unwrap();
+        let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE).unwrap();
+        let result =
+            (&groups_tree, &group_members_tree).transaction(|(groups_tree, group_members_tree)| {
+                current_members(groups_tree, group_members_tree, group_id, &snapshot)
+            });
+        assert!(matches!(
+            result,
+            Err(sled::transaction::TransactionError::Abort(Abort::Stale))
+        ));
+        let snapshot = crate::group::snapshot(&db, group_id).unwrap();
+        let result =
+            (&groups_tree, &group_members_tree).transaction(|(groups_tree, group_members_tree)| {
+                current_members(groups_tree, group_members_tree, group_id, &snapshot)
+            });
+        assert_eq!(result.ok().map(|members| members.len()), Some(2));
+    }
+
+    fn legacy_group(db: &sled::Db, users: serde_json::Value) -> u64 {
+        let group_id = db.generate_id().unwrap();
+        let group = serde_json::json!({ "name": "Old", "users": users });
+        db.open_tree(GROUPS_TREE)
+            .unwrap()
+            .insert(group_id.to_be_bytes(), serde_json::to_vec(&group).unwrap())
+            .unwrap();
+        group_id
+    }
+
+    #[test]
+    fn migrate_makes_lowest_admin_owner() {
+        let db = db();
+        let group_id = legacy_group(&db, serde_json::json!({"3": true, "5": false, "4": true}));
+
+        migrate(&db).unwrap();
+        let members = members(&db, group_id).unwrap();
+        assert_eq!(members.len(), 3);
+        assert_eq!(members[&3], Role::Owner);
+        assert_eq!(members[&4], Role::Admin);
+        assert_eq!(members[&5], Role::Member);
+        let group = db
+            .open_tree(GROUPS_TREE)
+            .unwrap()
+            .get(group_id.to_be_bytes())
+            .unwrap()
+            .unwrap();
+        let group: serde_json::Value = serde_json::from_slice(&group).unwrap();
+        assert!(group.get("users").is_none());
+    }
+
+    #[test]
+    fn migrate_keeps_roles() {
+        let db = db();
+        let group_id = legacy_group(&db, serde_json::json!({"3": "Admin", "5": "Owner"}));
+
+        migrate(&db).unwrap();
+        let members = members(&db, group_id).unwrap();
+        assert_eq!(members[&3], Role::Admin);
+        assert_eq!(members[&5], Role::Owner);
+    }
+
+    #[test]
+    fn migrate_only_once() {
+        let db = db();
+        crate::util::migrate_once(&db, "group_members", migrate).unwrap();
+        let group_id = legacy_group(&db, serde_json::json!({"3": true}));
+
+        crate::util::migrate_once(&db, "group_members", migrate).unwrap();
+        assert!(members(&db, group_id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn merge_moves_members_activities_and_subgroups() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let member = TestUser::new(&db, "member");
+        let invited = TestUser::new(&db, "invited");
+        let group_id = group(&db, &owner);
+        let merged_group_id = group(&db, &owner);
+        join(&db, merged_group_id, &owner, &member);
+        let params = SubgroupParams {
+            parent_id: merged_group_id,
+            name: "Subgroup".to_string(),
+        };
+        let response = run(create_subgroup(
+            owner.session(),
+            db.clone(),
+            crate::test_util::quotas(),
+            web::Json(params),
+        ))
+        .unwrap();
+        let subgroup_id: u64 = json(&response);
+        let params = GroupUserParams {
+            group_id: merged_group_id,
+            user_id: invited.id,
+        };
+        run(crate::invitation::create(
+            owner.session(),
+            db.clone(),
+            web::Json(params),
+        ))
+        .unwrap();
+        let params =
+            serde_json::from_value(serde_json::json!({ "group_id": merged_group_id })).unwrap();
+        run(crate::code::create(
+            owner.session(),
+            db.clone(),
+            web::Json(params),
+        ))
+        .unwrap();
+        // New activities make reads from before the transaction stale
+        let before = snapshot(&db, merged_group_id).unwrap();
+        let activity_id = crate::test_util::activity(&db, merged_group_id, &owner, 86400);
+        assert_ne!(
+            snapshot(&db, merged_group_id).unwrap().version,
+            before.version
+        );
+
+        let params = serde_json::from_value(serde_json::json!({
+            "group_id": group_id,
+            "merged_group_id": merged_group_id,
+        }))
+        .unwrap();
+        let response = run(merge(
+            owner.session(),
+            db.clone(),
+            crate::test_util::calendar(),
+            crate::test_util::quotas(),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert!(response.status().is_success());
+        let groups_tree = db.open_tree(GROUPS_TREE).unwrap();
+        assert!(groups_tree.group(merged_group_id).unwrap().is_none());
+        assert!(members(&db, merged_group_id).unwrap().is_empty());
+        let members = members(&db, group_id).unwrap();
+        assert_eq!(members.get(&owner.id), Some(&Role::Owner));
+        assert_eq!(members.get(&member.id), Some(&Role::Member));
+        assert_eq!(
+            crate::activity::upcoming(&db, group_id).unwrap(),
+            vec![activity_id]
+        );
+        let mut key = activity_id.to_be_bytes().to_vec();
+        key.extend_from_slice(&member.id.to_be_bytes());
+        assert!(db
+            .open_tree(crate::activity::ACTIVITIES_PARTICIPANTS_TREE)
+            .unwrap()
+            .contains_key(key)
+            .unwrap());
+        assert_eq!(
+            groups_tree.group(subgroup_id).unwrap().unwrap().parent,
+            Some(group_id)
+        );
+        assert_eq!(children(&db, group_id).unwrap(), vec![subgroup_id]);
+        let invites = invites(&db, merged_group_id).unwrap();
+        assert!(invites.invitations.is_empty());
+        assert!(invites.codes.is_empty());
+        assert!(db
+            .open_tree(crate::invitation::INVITATIONS_TREE)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn merge_refuses_own_subgroup() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let group_id = group(&db, &owner);
+        let params = SubgroupParams {
+            parent_id: group_id,
+            name: "Subgroup".to_string(),
+        };
+        let response = run(create_subgroup(
+            owner.session(),
+            db.clone(),
+            crate::test_util::quotas(),
+            web::Json(params),
+        ))
+        .unwrap();
+        let subgroup_id: u64 = json(&response);
+
+        let params = serde_json::from_value(serde_json::json!({
+            "group_id": subgroup_id,
+            "merged_group_id": group_id,
+        }))
+        .unwrap();
+        let response = run(merge(
+            owner.session(),
+            db.clone(),
+            crate::test_util::calendar(),
+            crate::test_util::quotas(),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert_eq!(response.status(), 400);
+    }
+
+    #[test]
+    fn archived_group_refuses_invites() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let invited = TestUser::new(&db, "invited");
+        let group_id = group(&db, &owner);
+        let params = serde_json::from_value(serde_json::json!({
+            "group_id": group_id,
+            "public": true,
+        }))
+        .unwrap();
+        run(set_settings(owner.session(), db.clone(), web::Json(params))).unwrap();
+        let params = GroupParams { group_id };
+        run(archive(owner.session(), db.clone(), web::Json(params))).unwrap();
+
+        let params = GroupUserParams {
+            group_id,
+            user_id: invited.id,
+        };
+        let response = run(crate::invitation::create(
+            owner.session(),
+            db.clone(),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert_eq!(response.status(), 403);
+        let params = serde_json::from_value(serde_json::json!({ "group_id": group_id })).unwrap();
+        let response = run(crate::code::create(
+            owner.session(),
+            db.clone(),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert_eq!(response.status(), 403);
+        let params = serde_json::from_value(serde_json::json!({ "group_id": group_id })).unwrap();
+        let response = run(crate::request::create(
+            invited.session(),
+            db.clone(),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert_eq!(response.status(), 403);
+        let invites = invites(&db, group_id).unwrap();
+        assert!(invites.invitations.is_empty());
+        assert!(invites.codes.is_empty());
+        assert!(invites.requests.is_empty());
+    }
+
+    #[test]
+    fn member_of_archived_subgroup_leaves_parent() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let member = TestUser::new(&db, "member");
+        let group_id = group(&db, &owner);
+        join(&db, group_id, &owner, &member);
+        let subgroup_id = subgroup(&db, group_id, &owner);
+        join(&db, subgroup_id, &owner, &member);
+        let activity_id = crate::test_util::activity(&db, subgroup_id, &owner, 86400);
+        let params = GroupParams {
+            group_id: subgroup_id,
+        };
+        run(archive(owner.session(), db.clone(), web::Json(params))).unwrap();
+
+        assert!(leave(&db, group_id, &member).status().is_success());
+        let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE).unwrap();
+        assert!(group_members_tree
+            .role(subgroup_id, member.id)
+            .unwrap()
+            .is_none());
+        // The answer to the archived activity stays
+        let mut key = member.id.to_be_bytes().to_vec();
+        key.extend_from_slice(&activity_id.to_be_bytes());
+        assert!(db
+            .open_tree(crate::activity::ACTIVITIES_USER_TREE)
+            .unwrap()
+            .contains_key(&key)
+            .unwrap());
+    }
+
+    #[test]
+    fn parent_admin_manages_subgroup() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let admin = TestUser::new(&db, "admin");
+        let group_id = group(&db, &owner);
+        join(&db, group_id, &owner, &admin);
+        let params = GroupUserParams {
+            group_id,
+            user_id: admin.id,
+        };
+        run(make_admin(owner.session(), db.clone(), web::Json(params))).unwrap();
+        let subgroup_id = subgroup(&db, group_id, &owner);
+
+        let params = serde_json::from_value(serde_json::json!({
+            "group_id": subgroup_id,
+            "public": true,
+        }))
+        .unwrap();
+        let response = run(set_settings(admin.session(), db.clone(), web::Json(params))).unwrap();
+        assert!(response.status().is_success());
+        let groups_tree = db.open_tree(GROUPS_TREE).unwrap();
+        assert!(
+            groups_tree
+                .group(subgroup_id)
+                .unwrap()
+                .unwrap()
+                .settings
+                .public
+        );
+        subgroup(&db, subgroup_id, &admin);
     }
 }
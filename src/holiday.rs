@@ -0,0 +1,229 @@
+use crate::{session::Session, util::Error};
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub const HOLIDAYS_USER_TREE: &[u8] = b"holidays_user";
+
+const BUNDLED: &str = include_str!("../data/holidays.json");
+
+/// A holiday on a fixed date, optionally only in one year, or a movable feast
+/// given in days after Easter Sunday.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Holiday {
+    Easter {
+        easter: i64,
+    },
+    Date {
+        #[serde(default)]
+        year: Option<i64>,
+        month: u32,
+        day: u32,
+    },
+}
+
+impl Holiday {
+    fn is_on(&self, days: i64, year: i64, month: u32, day: u32) -> bool {
+        match *self {
+            Holiday::Easter { easter } => days == easter_sunday(year) + easter,
+            Holiday::Date {
+                year: y,
+                month: m,
+                day: d,
+            } => m == month && d == day && y.unwrap_or(year) == year,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Region {
+    name: String,
+    days: Vec<Holiday>,
+}
+
+/// Public holidays of all known regions. Loaded from the file given in the
+/// `HOLIDAYS` environment variable, or the bundled `data/holidays.json`.
+#[derive(Deserialize, Clone)]
+#[serde(transparent)]
+pub struct Calendar {
+    regions: HashMap<String, Region>,
+}
+
+impl Calendar {
+    pub fn load() -> Calendar {
+        match std::env::var("HOLIDAYS") {
+            Ok(path) => {
+                let file = std::fs::read(path).expect("Could not read holidays");
+                serde_json::from_slice(&file).expect("Invalid holidays")
+            }
+            Err(_) => serde_json::from_str(BUNDLED).expect("Invalid bundled holidays"),
+        }
+    }
+
+    /// The holidays a user opted into, given the stored subscription.
+    pub fn holidays(&self, subscription: Option<sled::IVec>) -> serde_json::Result<Holidays<'_>> {
+        match subscription {
+            Some(subscription) => {
+                let subscription: Subscription = serde_json::from_slice(&subscription)?;
+                Ok(Holidays {
+                    region: self.regions.get(&subscription.region),
+                    mode: subscription.mode,
+                })
+            }
+            None => Ok(Holidays {
+                region: None,
+                mode: Mode::Free,
+            }),
+        }
+    }
+}
+
+/// Whether holidays are free time, overriding quiet hours, or blocked time.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Free,
+    Blocked,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Subscription {
+    region: String,
+    mode: Mode,
+}
+
+pub struct Holidays<'a> {
+    region: Option<&'a Region>,
+    mode: Mode,
+}
+
+impl Holidays<'_> {
+    /// How the given day, counted from 1970-01-01, is to be treated if it is
+    /// a holiday.
+    pub fn on(&self, days: i64) -> Option<Mode> {
+        let region = self.region?;
+        let (year, month, day) = civil_from_days(days);
+        if region.days.iter().any(|h| h.is_on(days, year, month, day)) {
+            Some(self.mode)
+        } else {
+            None
+        }
+    }
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date, see
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Converts a (year, month, day) date to days since 1970-01-01, see
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Easter Sunday of a year in days since 1970-01-01, using the anonymous
+/// Gregorian computus.
+fn easter_sunday(year: i64) -> i64 {
+    let a = year.rem_euclid(19);
+    let b = year.div_euclid(100);
+    let c = year.rem_euclid(100);
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15).rem_euclid(30);
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k).rem_euclid(7);
+    let m = (a + 11 * h + 22 * l) / 451;
+    let month = (h + l - 7 * m + 114) / 31;
+    let day = (h + l - 7 * m + 114) % 31 + 1;
+    days_from_civil(year, month as u32, day as u32)
+}
+
+pub async fn regions(calendar: web::Data<Calendar>) -> HttpResponse {
+    let regions: HashMap<&String, &String> = calendar
+        .regions
+        .iter()
+        .map(|(code, region)| (code, &region.name))
+        .collect();
+    HttpResponse::Ok().json(regions)
+}
+
+pub async fn subscribe(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    calendar: web::Data<Calendar>,
+    subscription: web::Json<Subscription>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    if !calendar.regions.contains_key(&subscription.region) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    let holidays_user_tree = db.open_tree(HOLIDAYS_USER_TREE)?;
+    let subscription = serde_json::to_vec(&subscription.into_inner())?;
+    holidays_user_tree.insert(user_id.to_be_bytes(), subscription)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn unsubscribe(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let holidays_user_tree = db.open_tree(HOLIDAYS_USER_TREE)?;
+    holidays_user_tree.remove(user_id.to_be_bytes())?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days_from_civil_inverts_civil_from_days() {
+        for days in [-719468, -1, 0, 18721, 20561, 2_000_000] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn easter_sunday_matches_known_dates() {
+        for &(year, month, day) in &[(2021, 4, 4), (2025, 4, 20), (2026, 4, 5), (2027, 3, 28)] {
+            assert_eq!(civil_from_days(easter_sunday(year)), (year, month, day));
+        }
+    }
+
+    #[test]
+    fn movable_feasts_follow_easter() {
+        let calendar: Calendar = serde_json::from_str(BUNDLED).unwrap();
+        let holidays = Holidays {
+            region: calendar.regions.get("DE"),
+            mode: Mode::Blocked,
+        };
+        // Karfreitag and Pfingstmontag 2026
+        assert!(holidays.on(days_from_civil(2026, 4, 3)).is_some());
+        assert!(holidays.on(days_from_civil(2026, 5, 25)).is_some());
+        assert!(holidays.on(days_from_civil(2026, 4, 2)).is_none());
+        // Fixed dates still apply
+        assert!(holidays.on(days_from_civil(2026, 10, 3)).is_some());
+    }
+}
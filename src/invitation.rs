@@ -0,0 +1,328 @@
+use crate::{
+    group::{Group, GroupUserParams, Members},
+    holiday::Calendar,
+    quota::Quotas,
+    role::{Permission, Role},
+    session::Session,
+    util::{abort_response, Abort, Error},
+};
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionalTree},
+    Transactional,
+};
+use std::{collections::HashMap, convert::TryInto};
+
+pub const INVITATIONS_TREE: &[u8] = b"invitations";
+pub const INVITATIONS_GROUP_TREE: &[u8] = b"invitations_group";
+
+#[derive(Serialize, Deserialize)]
+pub struct Invitation {
+    invited_by: u64,
+}
+
+/// The users invited to a group, since transactions can't scan.
+pub fn of_group(db: &sled::Db, group_id: u64) -> Result<Vec<u64>, Error> {
+    let invitations_group_tree = db.open_tree(INVITATIONS_GROUP_TREE)?;
+    invitations_group_tree
+        .scan_prefix(group_id.to_be_bytes())
+        .keys()
+        .map(|k| Ok(u64::from_be_bytes(k?[8..16].try_into().unwrap())))
+        .collect()
+}
+
+/// Deletes the invitations to a group returned by `of_group`.
+pub fn delete_all(
+    invitations_tree: &TransactionalTree,
+    invitations_group_tree: &TransactionalTree,
+    group_id: u64,
+    user_ids: &[u64],
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    for &user_id in user_ids {
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&user_id.to_be_bytes());
+        key.extend_from_slice(&group_id.to_be_bytes());
+        invitations_tree.remove(key)?;
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&group_id.to_be_bytes());
+        key.extend_from_slice(&user_id.to_be_bytes());
+        invitations_group_tree.remove(key)?;
+    }
+    Ok(())
+}
+
+/// Adds invitations stored before the group index existed to it.
+pub fn migrate(db: &sled::Db) -> Result<(), Error> {
+    let invitations_tree = db.open_tree(INVITATIONS_TREE)?;
+    let invitations_group_tree = db.open_tree(INVITATIONS_GROUP_TREE)?;
+    for k in invitations_tree.iter().keys() {
+        let k = k?;
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&k[8..16]);
+        key.extend_from_slice(&k[0..8]);
+        invitations_group_tree.insert(key, &[])?;
+    }
+    Ok(())
+}
+
+pub async fn create(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Json<GroupUserParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    let users_tree = db.open_tree(crate::user::USERS_TREE)?;
+    let invitations_tree = db.open_tree(INVITATIONS_TREE)?;
+    let invitations_group_tree = db.open_tree(INVITATIONS_GROUP_TREE)?;
+    let invitation = serde_json::to_vec(&Invitation {
+        invited_by: user_id,
+    })?;
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&params.user_id.to_be_bytes());
+    key.extend_from_slice(&params.group_id.to_be_bytes());
+    let mut group_key = Vec::with_capacity(16);
+    group_key.extend_from_slice(&params.group_id.to_be_bytes());
+    group_key.extend_from_slice(&params.user_id.to_be_bytes());
+
+    let result = (
+        &groups_tree,
+        &group_members_tree,
+        &users_tree,
+        &invitations_tree,
+        &invitations_group_tree,
+    )
+        .transaction(
+            |(
+                groups_tree,
+                group_members_tree,
+                users_tree,
+                invitations_tree,
+                invitations_group_tree,
+            )| {
+                let group = crate::group::bump_version(groups_tree, params.group_id)?;
+                match crate::group::role_in(
+                    groups_tree,
+                    group_members_tree,
+                    params.group_id,
+                    &group,
+                    user_id,
+                )? {
+                    Some(role) if role.can(Permission::ManageMembers) && !group.archived => {
+                        if users_tree.get(params.user_id.to_be_bytes())?.is_none() {
+                            sled::transaction::abort(Abort::NotFound)?;
+                        }
+                        if group_members_tree
+                            .role(params.group_id, params.user_id)?
+                            .is_some()
+                        {
+                            sled::transaction::abort(Abort::Conflict(Default::default()))?;
+                        }
+                        if !crate::group::in_parent(group_members_tree, &group, params.user_id)? {
+                            sled::transaction::abort(Abort::NotAllowed)?;
+                        }
+                        invitations_tree.insert(key.as_slice(), invitation.as_slice())?;
+                        invitations_group_tree.insert(group_key.as_slice(), &[])?;
+                        Ok(())
+                    }
+                    Some(_) => sled::transaction::abort(Abort::NotAllowed),
+                    None => sled::transaction::abort(Abort::NotFound),
+                }
+            },
+        );
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(sled::transaction::TransactionError::Abort(Abort::Conflict(_))) => {
+            Ok(HttpResponse::Conflict().finish())
+        }
+        Err(err) => abort_response(err),
+    }
+}
+
+#[derive(Serialize)]
+pub struct InvitationInfo {
+    name: String,
+    invited_by: u64,
+}
+
+pub async fn list(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let invitations_tree = db.open_tree(INVITATIONS_TREE)?;
+    let mut invitations = HashMap::new();
+    for res in invitations_tree.scan_prefix(user_id.to_be_bytes()) {
+        let (k, v) = res?;
+        let invitation: Invitation = serde_json::from_slice(&v)?;
+        // Groups may have been deleted since
+        if let Some(group) = groups_tree.get(&k[8..16])? {
+            let group: Group = serde_json::from_slice(&group)?;
+            let group_id = u64::from_be_bytes(k[8..16].try_into().unwrap());
+            invitations.insert(
+                group_id,
+                InvitationInfo {
+                    name: group.name,
+                    invited_by: invitation.invited_by,
+                },
+            );
+        }
+    }
+    Ok(HttpResponse::Ok().json(invitations))
+}
+
+#[derive(Deserialize)]
+pub struct InvitationParams {
+    group_id: u64,
+}
+
+pub async fn accept(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    calendar: web::Data<Calendar>,
+    quotas: web::Data<Quotas>,
+    params: web::Json<InvitationParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let groups_user_tree = db.open_tree(crate::group::GROUPS_USER_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    let invitations_tree = db.open_tree(INVITATIONS_TREE)?;
+    let invitations_group_tree = db.open_tree(INVITATIONS_GROUP_TREE)?;
+    let activities_tree = db.open_tree(crate::activity::ACTIVITIES_TREE)?;
+    let activities_user_tree = db.open_tree(crate::activity::ACTIVITIES_USER_TREE)?;
+    let activities_conflict_tree = db.open_tree(crate::activity::ACTIVITIES_CONFLICT_TREE)?;
+    let activities_participants_tree =
+        db.open_tree(crate::activity::ACTIVITIES_PARTICIPANTS_TREE)?;
+    let users_tree = db.open_tree(crate::user::USERS_TREE)?;
+    let templates_tree = db.open_tree(crate::template::TEMPLATES_TREE)?;
+    let holidays_user_tree = db.open_tree(crate::holiday::HOLIDAYS_USER_TREE)?;
+    let audit_log_tree = db.open_tree(crate::audit::AUDIT_LOG_TREE)?;
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&user_id.to_be_bytes());
+    key.extend_from_slice(&params.group_id.to_be_bytes());
+    let mut group_key = Vec::with_capacity(16);
+    group_key.extend_from_slice(&params.group_id.to_be_bytes());
+    group_key.extend_from_slice(&user_id.to_be_bytes());
+
+    // The group may change between reading it and the transaction
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        let snapshot = crate::group::snapshot(&db, params.group_id)?;
+        let upcoming = crate::activity::upcoming(&db, params.group_id)?;
+        let result = (
+            &groups_tree,
+            &groups_user_tree,
+            &group_members_tree,
+            &invitations_tree,
+            &invitations_group_tree,
+            &activities_tree,
+            &activities_user_tree,
+            &activities_conflict_tree,
+            &activities_participants_tree,
+            &users_tree,
+            &templates_tree,
+            &holidays_user_tree,
+            &audit_log_tree,
+        )
+            .transaction(
+                |(
+                    groups_tree,
+                    groups_user_tree,
+                    group_members_tree,
+                    invitations_tree,
+                    invitations_group_tree,
+                    activities_tree,
+                    activities_user_tree,
+                    activities_conflict_tree,
+                    activities_participants_tree,
+                    users_tree,
+                    templates_tree,
+                    holidays_user_tree,
+                    audit_log_tree,
+                )| {
+                    if invitations_tree.remove(key.as_slice())?.is_none() {
+                        sled::transaction::abort(Abort::NotFound)?;
+                    }
+                    invitations_group_tree.remove(group_key.as_slice())?;
+                    // The upcoming activities have to be complete
+                    crate::group::check_snapshot(groups_tree, params.group_id, &snapshot)?;
+                    if crate::group::add_member(
+                        groups_tree,
+                        groups_user_tree,
+                        group_members_tree,
+                        audit_log_tree,
+                        &quotas,
+                        params.group_id,
+                        user_id,
+                        Role::Member,
+                        user_id,
+                    )? {
+                        let availability = crate::activity::availability(
+                            users_tree,
+                            templates_tree,
+                            holidays_user_tree,
+                            &calendar,
+                            user_id,
+                        )?;
+                        crate::activity::add_participant(
+                            activities_tree,
+                            activities_user_tree,
+                            activities_conflict_tree,
+                            activities_participants_tree,
+                            user_id,
+                            &upcoming,
+                            &availability,
+                        )?;
+                    }
+                    Ok(())
+                },
+            );
+        if attempts == crate::group::ATTEMPTS
+            || !matches!(
+                result,
+                Err(sled::transaction::TransactionError::Abort(Abort::Stale))
+            )
+        {
+            break result;
+        }
+    };
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => abort_response(err),
+    }
+}
+
+pub async fn decline(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Json<InvitationParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let invitations_tree = db.open_tree(INVITATIONS_TREE)?;
+    let invitations_group_tree = db.open_tree(INVITATIONS_GROUP_TREE)?;
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&user_id.to_be_bytes());
+    key.extend_from_slice(&params.group_id.to_be_bytes());
+    let mut group_key = Vec::with_capacity(16);
+    group_key.extend_from_slice(&params.group_id.to_be_bytes());
+    group_key.extend_from_slice(&user_id.to_be_bytes());
+
+    let result = (&invitations_tree, &invitations_group_tree).transaction(
+        |(invitations_tree, invitations_group_tree)| {
+            if invitations_tree.remove(key.as_slice())?.is_none() {
+                sled::transaction::abort(Abort::NotFound)?;
+            }
+            invitations_group_tree.remove(group_key.as_slice())?;
+            Ok(())
+        },
+    );
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => abort_response(err),
+    }
+}
@@ -1,7 +1,20 @@
 mod activity;
+mod audit;
 mod block;
+mod code;
 mod group;
+mod holiday;
+mod invitation;
+mod notification;
+mod phrase;
+mod proposal;
+mod quota;
+mod request;
+mod role;
 mod session;
+mod template;
+#[cfg(test)]
+mod test_util;
 mod user;
 mod util;
 
@@ -13,6 +26,20 @@ async fn main() -> std::io::Result<()> {
     env_logger::init();
 
     let db = sled::open("./database").unwrap();
+    util::migrate_once(&db, "group_members", group::migrate).expect("Could not migrate groups");
+    util::migrate_once(&db, "activities_group", activity::migrate)
+        .expect("Could not migrate activities");
+    util::migrate_once(
+        &db,
+        "activities_participants",
+        activity::migrate_participants,
+    )
+    .expect("Could not migrate participants");
+    util::migrate_once(&db, "invitations_group", invitation::migrate)
+        .expect("Could not migrate invitations");
+    util::migrate_once(&db, "quotas", quota::migrate).expect("Could not migrate quotas");
+    let calendar = holiday::Calendar::load();
+    let quotas = quota::Quotas::load();
     HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default())
@@ -24,16 +51,55 @@ async fn main() -> std::io::Result<()> {
                     .route("/session", web::delete().to(user::logout))
                     .route("/block", web::post().to(user::add_block))
                     .route("/block", web::delete().to(user::remove_block))
+                    .route("/block/parse", web::post().to(phrase::parse))
+                    .route("/template", web::post().to(template::set))
+                    .route("/template", web::get().to(template::get))
+                    .route("/holiday", web::get().to(holiday::regions))
+                    .route("/holiday", web::post().to(holiday::subscribe))
+                    .route("/holiday", web::delete().to(holiday::unsubscribe))
                     .route("/group", web::post().to(group::create))
                     .route("/group", web::get().to(group::list))
-                    .route("/group/user", web::post().to(group::add_user))
+                    .route("/group", web::delete().to(group::remove))
+                    .route("/group/subgroup", web::post().to(group::create_subgroup))
+                    .route("/group/availability", web::get().to(group::availability))
+                    .route("/group/user", web::post().to(invitation::create))
                     .route("/group/user", web::delete().to(group::remove_user))
                     .route("/group/admin", web::post().to(group::make_admin))
+                    .route("/group/admin", web::delete().to(group::demote))
+                    .route("/group/role", web::post().to(group::set_role))
+                    .route("/group/owner", web::post().to(group::transfer_ownership))
+                    .route("/group/settings", web::post().to(group::set_settings))
+                    .route("/group/merge", web::post().to(group::merge))
+                    .route("/group/archive", web::post().to(group::archive))
+                    .route("/group/archive", web::delete().to(group::unarchive))
+                    .route("/group/code", web::post().to(code::create))
+                    .route("/group/code", web::get().to(code::list))
+                    .route("/group/code", web::delete().to(code::revoke))
+                    .route("/group/join", web::post().to(code::join))
+                    .route("/group/search", web::get().to(group::search))
+                    .route("/group/request", web::post().to(request::create))
+                    .route("/group/request", web::get().to(request::list))
+                    .route("/group/request", web::delete().to(request::reject))
+                    .route("/group/request/accept", web::post().to(request::accept))
+                    .route("/group/{group_id}", web::get().to(group::get))
+                    .route("/group/{group_id}", web::patch().to(group::update))
+                    .route("/group/{group_id}/log", web::get().to(audit::list))
+                    .route("/invitation", web::get().to(invitation::list))
+                    .route("/invitation", web::post().to(invitation::accept))
+                    .route("/invitation", web::delete().to(invitation::decline))
+                    .route("/notification", web::get().to(notification::list))
+                    .route("/notification", web::delete().to(notification::dismiss))
                     .route("/activity", web::post().to(activity::create))
                     .route("/activity", web::get().to(activity::list))
-                    .route("/activity/status", web::post().to(activity::change_status)),
+                    .route("/activity/proposal", web::get().to(proposal::list))
+                    .route("/activity/proposal", web::post().to(proposal::approve))
+                    .route("/activity/proposal", web::delete().to(proposal::reject))
+                    .route("/activity/status", web::post().to(activity::change_status))
+                    .route("/activity/{activity_id}", web::patch().to(activity::update)),
             )
             .data(db.clone())
+            .data(calendar.clone())
+            .data(quotas.clone())
     })
     .bind("127.0.0.1:8080")?
     .run()
@@ -0,0 +1,95 @@
+use crate::{
+    session::Session,
+    util::{now, Abort, Error},
+};
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionalTree};
+use std::{collections::HashMap, convert::TryInto};
+
+pub const NOTIFICATIONS_TREE: &[u8] = b"notifications";
+
+#[derive(Serialize, Deserialize)]
+pub enum Event {
+    /// A group the user was a member of has been deleted.
+    GroupDeleted { group_id: u64, name: String },
+    /// A group the user was a member of has been merged into another group,
+    /// which the user is now a member of.
+    GroupMerged {
+        group_id: u64,
+        name: String,
+        merged_into: u64,
+    },
+    /// A proposed activity has been approved.
+    ProposalApproved {
+        group_id: u64,
+        proposal_id: u64,
+        activity_id: u64,
+    },
+    /// A proposed activity has been rejected.
+    ProposalRejected { group_id: u64, proposal_id: u64 },
+    /// A request to join a group has been accepted.
+    JoinRequestAccepted { group_id: u64 },
+    /// A request to join a group has been rejected.
+    JoinRequestRejected { group_id: u64 },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Notification {
+    time: u64,
+    event: Event,
+}
+
+/// Stores a notification for a user.
+pub fn notify(
+    notifications_tree: &TransactionalTree,
+    user_id: u64,
+    event: Event,
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    let notification_id = notifications_tree.generate_id()?;
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&user_id.to_be_bytes());
+    key.extend_from_slice(&notification_id.to_be_bytes());
+    let notification = serde_json::to_vec(&Notification { time: now(), event })
+        .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+    notifications_tree.insert(key, notification)?;
+    Ok(())
+}
+
+pub async fn list(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let notifications_tree = db.open_tree(NOTIFICATIONS_TREE)?;
+    let notifications = notifications_tree
+        .scan_prefix(user_id.to_be_bytes())
+        .map(|res| -> Result<(u64, Notification), Error> {
+            let (k, v) = res?;
+            let notification_id = u64::from_be_bytes(k[8..16].try_into().unwrap());
+            Ok((notification_id, serde_json::from_slice(&v)?))
+        })
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    Ok(HttpResponse::Ok().json(notifications))
+}
+
+#[derive(Deserialize)]
+pub struct NotificationParams {
+    notification_id: u64,
+}
+
+pub async fn dismiss(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Json<NotificationParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let notifications_tree = db.open_tree(NOTIFICATIONS_TREE)?;
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&user_id.to_be_bytes());
+    key.extend_from_slice(&params.notification_id.to_be_bytes());
+    match notifications_tree.remove(key)? {
+        Some(_) => Ok(HttpResponse::Ok().finish()),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
@@ -0,0 +1,335 @@
+use crate::{
+    block::{Block, Level},
+    session::Session,
+    template::{Template, Weekday},
+    user::BlockOptions,
+    util::{abort_response, now, Error},
+};
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+const DAY: i64 = 24 * 60 * 60;
+
+/// How many weeks recurring phrases may be repeated for.
+const MAX_WEEKS: u32 = 52;
+
+const WEEKDAYS: [Weekday; 5] = [
+    Weekday::Monday,
+    Weekday::Tuesday,
+    Weekday::Wednesday,
+    Weekday::Thursday,
+    Weekday::Friday,
+];
+const WEEKEND: [Weekday; 2] = [Weekday::Saturday, Weekday::Sunday];
+
+/// The days a phrase refers to, counted from 1970-01-01 in local time.
+enum Days {
+    On(Vec<i64>),
+    Weekly(Vec<Weekday>),
+}
+
+/// A parsed phrase like "tomorrow 3pm-5pm" or "every tuesday evening".
+/// `start` and `end` are minutes after midnight, `end` may lie on the next
+/// day.
+struct Phrase {
+    days: Days,
+    start: u32,
+    end: u32,
+}
+
+impl Phrase {
+    fn parse(text: &str, today: i64) -> Result<Phrase, String> {
+        let mut on = Vec::new();
+        let mut weekdays = Vec::new();
+        let mut every = false;
+        let mut next = false;
+        let mut times = Vec::new();
+        let mut part = None;
+        for token in tokens(text) {
+            match token.as_str() {
+                "every" | "each" => every = true,
+                "next" => next = true,
+                "today" => on.push(today),
+                "tomorrow" => on.push(today + 1),
+                "day" | "days" if every => {
+                    weekdays.extend(&WEEKDAYS);
+                    weekdays.extend(&WEEKEND)
+                }
+                "day" | "days" => (),
+                "weekday" | "weekdays" => {
+                    every = true;
+                    weekdays.extend(&WEEKDAYS)
+                }
+                "weekend" | "weekends" => {
+                    every = true;
+                    weekdays.extend(&WEEKEND)
+                }
+                "morning" => part = Some((8 * 60, 12 * 60)),
+                "afternoon" => part = Some((12 * 60, 17 * 60)),
+                "evening" => part = Some((17 * 60, 22 * 60)),
+                "night" => part = Some((22 * 60, 30 * 60)),
+                "all" | "whole" | "from" | "to" | "until" | "till" | "at" | "on" | "in" | "the"
+                | "and" | "-" => (),
+                word => {
+                    if let Some(weekday) = weekday(word) {
+                        weekdays.push(weekday);
+                    } else if let Some(day) = date(word) {
+                        on.push(day);
+                    } else if let Some(time) = time(word) {
+                        times.push(time);
+                    } else {
+                        return Err(format!("Unknown word \"{}\"", word));
+                    }
+                }
+            }
+        }
+        let days = match (every, on.is_empty()) {
+            (true, true) if !weekdays.is_empty() => Days::Weekly(weekdays),
+            (false, _) if !weekdays.is_empty() => {
+                if !on.is_empty() {
+                    return Err("Either give weekdays or dates".into());
+                }
+                Days::On(
+                    weekdays
+                        .into_iter()
+                        .map(|weekday| {
+                            let mut day = if next { today + 1 } else { today };
+                            while Weekday::from_day(day) != weekday {
+                                day += 1;
+                            }
+                            day
+                        })
+                        .collect(),
+                )
+            }
+            (false, false) => Days::On(on),
+            _ => return Err("Missing day".into()),
+        };
+        let (start, end) = match (times.as_slice(), part) {
+            (&[], Some(part)) => part,
+            (&[], None) => (0, 24 * 60),
+            (&[(start, start_meridiem), (end, end_meridiem)], None) => {
+                // "3-5pm" means 15:00 to 17:00
+                let start = if start_meridiem.is_none()
+                    && end_meridiem == Some(true)
+                    && start + 12 * 60 <= end
+                {
+                    start + 12 * 60
+                } else {
+                    start
+                };
+                if end > start {
+                    (start, end)
+                } else {
+                    (start, end + 24 * 60)
+                }
+            }
+            (&[_], _) => return Err("Missing end time".into()),
+            _ => return Err("Give either a time range or a part of the day".into()),
+        };
+        Ok(Phrase { days, start, end })
+    }
+
+    /// The blocks this phrase refers to. Weekly phrases are repeated for the
+    /// given number of weeks starting today.
+    fn blocks(&self, today: i64, weeks: u32, utc_offset: i32) -> Result<Vec<Block>, String> {
+        let days = match &self.days {
+            Days::On(days) => days.clone(),
+            Days::Weekly(weekdays) => (today..today + 7 * weeks as i64)
+                .filter(|&day| weekdays.contains(&Weekday::from_day(day)))
+                .collect(),
+        };
+        let offset = utc_offset as i64 * 60;
+        days.into_iter()
+            .map(|day| {
+                let start = day * DAY + self.start as i64 * 60 - offset;
+                let end = day * DAY + self.end as i64 * 60 - offset;
+                if start < 0 {
+                    return Err("Times before 1970 are not supported".into());
+                }
+                Ok(Block::new(start as u64, end as u64))
+            })
+            .collect()
+    }
+}
+
+/// Splits a phrase into lower case words, keeping dashes of time ranges as
+/// separate words and joining "am" and "pm" with the preceding time.
+fn tokens(text: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    for word in text.to_lowercase().split_whitespace() {
+        let word = word.trim_matches([',', '.']);
+        if date(word).is_some() {
+            tokens.push(word.to_string());
+            continue;
+        }
+        for (i, part) in word.split(['-', '–']).enumerate() {
+            if i > 0 {
+                tokens.push("-".to_string());
+            }
+            if part.is_empty() {
+                continue;
+            }
+            match tokens.last_mut() {
+                Some(last) if (part == "am" || part == "pm") && time(last).is_some() => {
+                    last.push_str(part)
+                }
+                _ => tokens.push(part.to_string()),
+            }
+        }
+    }
+    tokens
+}
+
+fn weekday(word: &str) -> Option<Weekday> {
+    let word = word.strip_suffix('s').unwrap_or(word);
+    match word {
+        "mon" | "monday" => Some(Weekday::Monday),
+        "tue" | "tues" | "tuesday" => Some(Weekday::Tuesday),
+        "wed" | "wednesday" => Some(Weekday::Wednesday),
+        "thu" | "thur" | "thurs" | "thursday" => Some(Weekday::Thursday),
+        "fri" | "friday" => Some(Weekday::Friday),
+        "sat" | "saturday" => Some(Weekday::Saturday),
+        "sun" | "sunday" => Some(Weekday::Sunday),
+        _ => None,
+    }
+}
+
+/// Parses an ISO date like "2021-03-05" to days since 1970-01-01.
+fn date(word: &str) -> Option<i64> {
+    let mut parts = word.splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: u32 = parts.next()?.parse().ok()?;
+    let day: u32 = parts.next()?.parse().ok()?;
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=days_in_month).contains(&day)
+    {
+        return None;
+    }
+    Some(crate::holiday::days_from_civil(year, month, day))
+}
+
+/// Parses a time like "15:30", "3pm" or "noon" to minutes after midnight,
+/// along with whether it was given as pm, am or neither.
+fn time(word: &str) -> Option<(u32, Option<bool>)> {
+    match word {
+        "noon" => return Some((12 * 60, None)),
+        "midnight" => return Some((0, None)),
+        _ => (),
+    }
+    let (word, pm) = if let Some(word) = word.strip_suffix("pm") {
+        (word, Some(true))
+    } else if let Some(word) = word.strip_suffix("am") {
+        (word, Some(false))
+    } else {
+        (word.strip_suffix('h').unwrap_or(word), None)
+    };
+    let (hours, minutes) = match word.split_once(':') {
+        Some((hours, minutes)) => (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?),
+        None => (word.parse::<u32>().ok()?, 0),
+    };
+    if minutes >= 60 || hours > 24 || (pm.is_some() && !(1..=12).contains(&hours)) {
+        return None;
+    }
+    let hours = match pm {
+        Some(true) if hours < 12 => hours + 12,
+        Some(false) if hours == 12 => 0,
+        _ => hours,
+    };
+    Some((hours * 60 + minutes, pm))
+}
+
+#[derive(Deserialize)]
+pub struct PhraseParams {
+    text: String,
+    #[serde(default)]
+    level: Level,
+    #[serde(default)]
+    create: bool,
+    #[serde(default = "default_weeks")]
+    weeks: u32,
+}
+
+fn default_weeks() -> u32 {
+    4
+}
+
+#[derive(Serialize)]
+pub struct Parsed {
+    blocks: Vec<Block>,
+    activities: Vec<u64>,
+}
+
+pub async fn parse(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    options: web::Query<BlockOptions>,
+    params: web::Json<PhraseParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let templates_tree = db.open_tree(crate::template::TEMPLATES_TREE)?;
+    let template = Template::from_value(templates_tree.get(user_id.to_be_bytes())?)?;
+    let today = (now() as i64 + template.utc_offset as i64 * 60).div_euclid(DAY);
+    if params.weeks > MAX_WEEKS {
+        return Ok(HttpResponse::BadRequest().json(format!("At most {} weeks", MAX_WEEKS)));
+    }
+    let blocks = match Phrase::parse(&params.text, today)
+        .and_then(|phrase| phrase.blocks(today, params.weeks, template.utc_offset))
+    {
+        Ok(blocks) => blocks,
+        Err(message) => return Ok(HttpResponse::BadRequest().json(message)),
+    };
+    let blocks: Vec<Block> = blocks
+        .into_iter()
+        .map(|block| block.with_level(params.level))
+        .collect();
+    if !params.create {
+        return Ok(HttpResponse::Ok().json(Parsed {
+            blocks,
+            activities: Vec::new(),
+        }));
+    }
+    match crate::user::add_blocks(&db, user_id, &blocks, &options)? {
+        Ok(activities) => Ok(HttpResponse::Ok().json(Parsed { blocks, activities })),
+        Err(err) => abort_response(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_checks_the_day_of_the_month() {
+        assert_eq!(date("2021-03-05"), Some(18691));
+        assert_eq!(date("2024-02-29"), Some(19782));
+        assert_eq!(date("2023-02-29"), None);
+        assert_eq!(date("2021-02-31"), None);
+        assert_eq!(date("2021-04-31"), None);
+        assert_eq!(date("2021-13-01"), None);
+    }
+
+    #[test]
+    fn blocks_before_1970_are_refused() {
+        let phrase = Phrase::parse("1969-12-31 evening", 18691).unwrap();
+        assert!(phrase.blocks(18691, 4, 0).is_err());
+        let phrase = Phrase::parse("1970-01-01 1am-2am", 18691).unwrap();
+        assert!(phrase.blocks(18691, 4, 120).is_err());
+        assert_eq!(phrase.blocks(18691, 4, 0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn weekly_phrases_repeat_for_the_given_weeks() {
+        let phrase = Phrase::parse("every day evening", 18691).unwrap();
+        let blocks = phrase.blocks(18691, MAX_WEEKS, 0).unwrap();
+        assert_eq!(blocks.len(), 7 * MAX_WEEKS as usize);
+    }
+}
@@ -0,0 +1,305 @@
+use crate::{
+    activity::Activity,
+    group::{Group, GroupParams},
+    holiday::Calendar,
+    notification::Event,
+    quota::{Quota, Quotas},
+    role::Permission,
+    session::Session,
+    util::{abort_response, Abort, Error},
+};
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionalTree},
+    Transactional,
+};
+use std::{collections::HashMap, convert::TryInto};
+
+pub const PROPOSALS_TREE: &[u8] = b"proposals";
+
+/// An activity proposed by a member which has to be approved before anyone is
+/// invited.
+#[derive(Serialize, Deserialize)]
+pub struct Proposal {
+    proposed_by: u64,
+    activity: Activity,
+}
+
+/// Queues an activity for approval. Returns the id of the proposal.
+pub fn propose(
+    proposals_tree: &TransactionalTree,
+    user_id: u64,
+    activity: &Activity,
+) -> Result<u64, ConflictableTransactionError<Abort>> {
+    let proposal_id = proposals_tree.generate_id()?;
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&activity.group_id().to_be_bytes());
+    key.extend_from_slice(&proposal_id.to_be_bytes());
+    let proposal = serde_json::to_vec(&Proposal {
+        proposed_by: user_id,
+        activity: activity.clone(),
+    })
+    .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+    proposals_tree.insert(key, proposal)?;
+    Ok(proposal_id)
+}
+
+/// Moves a proposal to another group, keeping its id.
+pub fn move_to(
+    proposals_tree: &TransactionalTree,
+    key: &[u8],
+    group_id: u64,
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    let proposal = match proposals_tree.remove(key)? {
+        Some(proposal) => proposal,
+        None => return Ok(()),
+    };
+    let mut proposal: Proposal = serde_json::from_slice(&proposal)
+        .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+    proposal.activity.set_group_id(group_id);
+    let mut new_key = Vec::with_capacity(16);
+    new_key.extend_from_slice(&group_id.to_be_bytes());
+    new_key.extend_from_slice(&key[8..16]);
+    let proposal = serde_json::to_vec(&proposal)
+        .map_err(|err| ConflictableTransactionError::Abort(Abort::SerdeError(err)))?;
+    proposals_tree.insert(new_key, proposal)?;
+    Ok(())
+}
+
+pub async fn list(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Query<GroupParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    let proposals_tree = db.open_tree(PROPOSALS_TREE)?;
+    let group = match groups_tree.get(params.group_id.to_be_bytes())? {
+        Some(group) => group,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let group: Group = serde_json::from_slice(&group)?;
+    match crate::group::role_in(
+        &groups_tree,
+        &group_members_tree,
+        params.group_id,
+        &group,
+        user_id,
+    )? {
+        Some(role) if role.can(Permission::ApproveActivities) => (),
+        Some(_) => return Ok(HttpResponse::Forbidden().finish()),
+        None => return Ok(HttpResponse::NotFound().finish()),
+    }
+    let proposals = proposals_tree
+        .scan_prefix(params.group_id.to_be_bytes())
+        .map(|res| -> Result<(u64, Proposal), Error> {
+            let (k, v) = res?;
+            let proposal_id = u64::from_be_bytes(k[8..16].try_into().unwrap());
+            Ok((proposal_id, serde_json::from_slice(&v)?))
+        })
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    Ok(HttpResponse::Ok().json(proposals))
+}
+
+#[derive(Deserialize)]
+pub struct ProposalParams {
+    group_id: u64,
+    proposal_id: u64,
+}
+
+pub async fn approve(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    calendar: web::Data<Calendar>,
+    quotas: web::Data<Quotas>,
+    params: web::Json<ProposalParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let proposals_tree = db.open_tree(PROPOSALS_TREE)?;
+    let notifications_tree = db.open_tree(crate::notification::NOTIFICATIONS_TREE)?;
+    let activities_tree = db.open_tree(crate::activity::ACTIVITIES_TREE)?;
+    let activities_user_tree = db.open_tree(crate::activity::ACTIVITIES_USER_TREE)?;
+    let activities_conflict_tree = db.open_tree(crate::activity::ACTIVITIES_CONFLICT_TREE)?;
+    let activities_participants_tree =
+        db.open_tree(crate::activity::ACTIVITIES_PARTICIPANTS_TREE)?;
+    let activities_group_tree = db.open_tree(crate::activity::ACTIVITIES_GROUP_TREE)?;
+    let users_tree = db.open_tree(crate::user::USERS_TREE)?;
+    let templates_tree = db.open_tree(crate::template::TEMPLATES_TREE)?;
+    let holidays_user_tree = db.open_tree(crate::holiday::HOLIDAYS_USER_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    // The group may change between reading it and the transaction
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        let snapshot = crate::group::snapshot(&db, params.group_id)?;
+        let upcoming = crate::activity::upcoming(&db, params.group_id)?;
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&params.group_id.to_be_bytes());
+        key.extend_from_slice(&params.proposal_id.to_be_bytes());
+
+        let result = (
+            &groups_tree,
+            &proposals_tree,
+            &notifications_tree,
+            &activities_tree,
+            &activities_user_tree,
+            &activities_conflict_tree,
+            &activities_participants_tree,
+            &activities_group_tree,
+            &users_tree,
+            &templates_tree,
+            &holidays_user_tree,
+            &group_members_tree,
+        )
+            .transaction(
+                |(
+                    groups_tree,
+                    proposals_tree,
+                    notifications_tree,
+                    activities_tree,
+                    activities_user_tree,
+                    activities_conflict_tree,
+                    activities_participants_tree,
+                    activities_group_tree,
+                    users_tree,
+                    templates_tree,
+                    holidays_user_tree,
+                    group_members_tree,
+                )| {
+                    let members = crate::group::current_members(
+                        groups_tree,
+                        group_members_tree,
+                        params.group_id,
+                        &snapshot,
+                    )?;
+                    let group = crate::group::bump_version(groups_tree, params.group_id)?;
+                    match crate::group::role_in(
+                        groups_tree,
+                        group_members_tree,
+                        params.group_id,
+                        &group,
+                        user_id,
+                    )? {
+                        Some(role) if role.can(Permission::ApproveActivities) => (),
+                        Some(_) => sled::transaction::abort(Abort::NotAllowed)?,
+                        None => sled::transaction::abort(Abort::NotFound)?,
+                    }
+                    if group.archived {
+                        sled::transaction::abort(Abort::NotAllowed)?;
+                    }
+                    quotas.check(
+                        Quota::OpenActivities,
+                        crate::activity::remaining(activities_tree, &upcoming)?,
+                    )?;
+                    let proposal = proposals_tree.remove(key.as_slice())?.ok_or(
+                        sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
+                    )?;
+                    let proposal: Proposal = serde_json::from_slice(&proposal).map_err(|err| {
+                        sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(
+                            err,
+                        ))
+                    })?;
+                    let activity_id = crate::activity::insert(
+                        activities_tree,
+                        activities_user_tree,
+                        activities_conflict_tree,
+                        activities_participants_tree,
+                        activities_group_tree,
+                        users_tree,
+                        templates_tree,
+                        holidays_user_tree,
+                        &calendar,
+                        &members,
+                        proposal.proposed_by,
+                        &proposal.activity,
+                    )?;
+                    crate::notification::notify(
+                        notifications_tree,
+                        proposal.proposed_by,
+                        Event::ProposalApproved {
+                            group_id: params.group_id,
+                            proposal_id: params.proposal_id,
+                            activity_id,
+                        },
+                    )?;
+                    Ok(activity_id)
+                },
+            );
+        if attempts == crate::group::ATTEMPTS
+            || !matches!(
+                result,
+                Err(sled::transaction::TransactionError::Abort(Abort::Stale))
+            )
+        {
+            break result;
+        }
+    };
+    match result {
+        Ok(activity_id) => Ok(HttpResponse::Ok().json(activity_id)),
+        Err(err) => abort_response(err),
+    }
+}
+
+pub async fn reject(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Json<ProposalParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    let proposals_tree = db.open_tree(PROPOSALS_TREE)?;
+    let notifications_tree = db.open_tree(crate::notification::NOTIFICATIONS_TREE)?;
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&params.group_id.to_be_bytes());
+    key.extend_from_slice(&params.proposal_id.to_be_bytes());
+
+    let result = (
+        &groups_tree,
+        &group_members_tree,
+        &proposals_tree,
+        &notifications_tree,
+    )
+        .transaction(
+            |(groups_tree, group_members_tree, proposals_tree, notifications_tree)| {
+                let group = groups_tree.get(params.group_id.to_be_bytes())?.ok_or(
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
+                )?;
+                let group: Group = serde_json::from_slice(&group).map_err(|err| {
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(err))
+                })?;
+                match crate::group::role_in(
+                    groups_tree,
+                    group_members_tree,
+                    params.group_id,
+                    &group,
+                    user_id,
+                )? {
+                    Some(role) if role.can(Permission::ApproveActivities) => (),
+                    Some(_) => sled::transaction::abort(Abort::NotAllowed)?,
+                    None => sled::transaction::abort(Abort::NotFound)?,
+                }
+                let proposal = proposals_tree.remove(key.as_slice())?.ok_or(
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
+                )?;
+                let proposal: Proposal = serde_json::from_slice(&proposal).map_err(|err| {
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(err))
+                })?;
+                crate::notification::notify(
+                    notifications_tree,
+                    proposal.proposed_by,
+                    Event::ProposalRejected {
+                        group_id: params.group_id,
+                        proposal_id: params.proposal_id,
+                    },
+                )
+            },
+        );
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => abort_response(err),
+    }
+}
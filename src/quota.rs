@@ -0,0 +1,333 @@
+use crate::{
+    group::{Group, GROUPS_TREE, GROUP_MEMBERS_TREE},
+    role::Role,
+    util::{now, Abort, Error},
+};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionalTree};
+use std::{collections::HashMap, convert::TryInto};
+
+pub const ACTIVITIES_CREATED_TREE: &[u8] = b"activities_created";
+pub const GROUPS_OWNED_TREE: &[u8] = b"groups_owned";
+
+/// A quota which would be exceeded by a request, sent with FORBIDDEN or, for
+/// `ActivitiesPerDay`, TOO MANY REQUESTS responses.
+#[derive(Serialize, Clone, Copy)]
+pub enum Quota {
+    GroupsOwned,
+    Members,
+    OpenActivities,
+    ActivitiesPerDay,
+}
+
+impl Quota {
+    /// Whether the quota frees up by itself at the start of the next day.
+    pub fn resets_daily(self) -> bool {
+        matches!(self, Quota::ActivitiesPerDay)
+    }
+}
+
+/// Limits on how much users and groups may create. Loaded from the file given
+/// in the `QUOTAS` environment variable, limits missing there keep their
+/// defaults. A limit of 0 means there is no limit.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Quotas {
+    /// Groups a user may own, counting subgroups.
+    groups_owned: u64,
+    /// Members a group may have.
+    members: u64,
+    /// Activities of a group which have not started yet.
+    open_activities: u64,
+    /// Activities a user may create per day, counting proposals.
+    activities_per_day: u64,
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Quotas {
+            groups_owned: 100,
+            members: 1000,
+            open_activities: 1000,
+            activities_per_day: 100,
+        }
+    }
+}
+
+impl Quotas {
+    pub fn load() -> Quotas {
+        match std::env::var("QUOTAS") {
+            Ok(path) => {
+                let file = std::fs::read(path).expect("Could not read quotas");
+                serde_json::from_slice(&file).expect("Invalid quotas")
+            }
+            Err(_) => Quotas::default(),
+        }
+    }
+
+    /// Aborts the transaction if `used` already reached the limit of `quota`.
+    pub fn check(
+        &self,
+        quota: Quota,
+        used: u64,
+    ) -> Result<(), ConflictableTransactionError<Abort>> {
+        let limit = match quota {
+            Quota::GroupsOwned => self.groups_owned,
+            Quota::Members => self.members,
+            Quota::OpenActivities => self.open_activities,
+            Quota::ActivitiesPerDay => self.activities_per_day,
+        };
+        if limit != 0 && used >= limit {
+            sled::transaction::abort(Abort::QuotaExceeded(quota))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Counts an activity created by a user today, unless the user already
+    /// created as many as allowed. Days start at midnight UTC. Only the count
+    /// of the last day a user created an activity on is kept.
+    pub fn count_activity(
+        &self,
+        activities_created_tree: &TransactionalTree,
+        user_id: u64,
+    ) -> Result<(), ConflictableTransactionError<Abort>> {
+        let today = now() / 86400;
+        let created = match activities_created_tree.get(user_id.to_be_bytes())? {
+            Some(v) if u64::from_be_bytes(v[..8].try_into().unwrap()) == today => {
+                u64::from_be_bytes(v[8..16].try_into().unwrap())
+            }
+            _ => 0,
+        };
+        self.check(Quota::ActivitiesPerDay, created)?;
+        let mut value = Vec::with_capacity(16);
+        value.extend_from_slice(&today.to_be_bytes());
+        value.extend_from_slice(&(created + 1).to_be_bytes());
+        activities_created_tree.insert(&user_id.to_be_bytes(), value)?;
+        Ok(())
+    }
+
+    /// Counts a group created by a user, unless the user already owns as many
+    /// as allowed.
+    pub fn count_owned(
+        &self,
+        groups_owned_tree: &TransactionalTree,
+        user_id: u64,
+    ) -> Result<(), ConflictableTransactionError<Abort>> {
+        self.check(Quota::GroupsOwned, owned(groups_owned_tree, user_id)?)?;
+        change_owned(groups_owned_tree, user_id, true)
+    }
+}
+
+/// How many groups a user owns.
+fn owned(
+    groups_owned_tree: &TransactionalTree,
+    user_id: u64,
+) -> Result<u64, ConflictableTransactionError<Abort>> {
+    Ok(match groups_owned_tree.get(user_id.to_be_bytes())? {
+        Some(v) => u64::from_be_bytes(v.as_ref().try_into().unwrap()),
+        None => 0,
+    })
+}
+
+/// Counts a group a user became the owner of, or, if `gained` is false, no
+/// longer owns.
+pub fn change_owned(
+    groups_owned_tree: &TransactionalTree,
+    user_id: u64,
+    gained: bool,
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    let owned = owned(groups_owned_tree, user_id)?;
+    let owned = if gained {
+        owned + 1
+    } else {
+        owned.saturating_sub(1)
+    };
+    groups_owned_tree.insert(&user_id.to_be_bytes(), &owned.to_be_bytes())?;
+    Ok(())
+}
+
+/// Counts the groups owned by each user and the members of each group. The
+/// counts of created activities are dropped, they used to be kept for every
+/// day.
+pub fn migrate(db: &sled::Db) -> Result<(), Error> {
+    let groups_tree = db.open_tree(GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(GROUP_MEMBERS_TREE)?;
+    let groups_owned_tree = db.open_tree(GROUPS_OWNED_TREE)?;
+    let activities_created_tree = db.open_tree(ACTIVITIES_CREATED_TREE)?;
+    let mut owned: HashMap<u64, u64> = HashMap::new();
+    let mut members: HashMap<u64, u64> = HashMap::new();
+    for res in group_members_tree.iter() {
+        let (k, v) = res?;
+        let group_id = u64::from_be_bytes(k[..8].try_into().unwrap());
+        let user_id = u64::from_be_bytes(k[8..16].try_into().unwrap());
+        *members.entry(group_id).or_default() += 1;
+        if serde_json::from_slice::<Role>(&v)? == Role::Owner {
+            *owned.entry(user_id).or_default() += 1;
+        }
+    }
+    for (user_id, owned) in owned {
+        groups_owned_tree.insert(user_id.to_be_bytes(), &owned.to_be_bytes())?;
+    }
+    for res in groups_tree.iter() {
+        let (k, v) = res?;
+        let group_id = u64::from_be_bytes(k.as_ref().try_into().unwrap());
+        let mut group: Group = serde_json::from_slice(&v)?;
+        group.member_count = members.get(&group_id).copied().unwrap_or(0);
+        groups_tree.insert(k, serde_json::to_vec(&group)?)?;
+    }
+    activities_created_tree.clear()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{activity, db, group, join, json, run, TestUser};
+    use actix_web::{web, HttpResponse};
+
+    fn create_group(db: &web::Data<sled::Db>, quotas: &Quotas, owner: &TestUser) -> HttpResponse {
+        run(crate::group::create(
+            owner.session(),
+            db.clone(),
+            web::Data::new(quotas.clone()),
+            web::Json("Group".to_string()),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn deleting_a_group_frees_its_quota() {
+        let db = db();
+        let quotas = Quotas {
+            groups_owned: 1,
+            ..Quotas::default()
+        };
+        let owner = TestUser::new(&db, "owner");
+        let response = create_group(&db, &quotas, &owner);
+        assert_eq!(response.status(), 200);
+        let group_id: u64 = json(&response);
+
+        let response = create_group(&db, &quotas, &owner);
+        assert_eq!(response.status(), 403);
+        assert_eq!(json::<String>(&response), "GroupsOwned");
+
+        let params = serde_json::from_value(serde_json::json!({ "group_id": group_id })).unwrap();
+        let response = run(crate::group::remove(
+            owner.session(),
+            db.clone(),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(create_group(&db, &quotas, &owner).status(), 200);
+    }
+
+    #[test]
+    fn transfer_moves_ownership_quota() {
+        let db = db();
+        let quotas = Quotas {
+            groups_owned: 1,
+            ..Quotas::default()
+        };
+        let owner = TestUser::new(&db, "owner");
+        let member = TestUser::new(&db, "member");
+        let group_id = group(&db, &owner);
+        join(&db, group_id, &owner, &member);
+
+        let params = serde_json::from_value(serde_json::json!({
+            "group_id": group_id,
+            "user_id": member.id,
+        }))
+        .unwrap();
+        let response = run(crate::group::transfer_ownership(
+            owner.session(),
+            db.clone(),
+            web::Data::new(quotas.clone()),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert!(response.status().is_success());
+
+        assert_eq!(create_group(&db, &quotas, &owner).status(), 200);
+        let response = create_group(&db, &quotas, &member);
+        assert_eq!(response.status(), 403);
+        assert_eq!(json::<String>(&response), "GroupsOwned");
+    }
+
+    #[test]
+    fn members_are_limited() {
+        let db = db();
+        let quotas = Quotas {
+            members: 2,
+            ..Quotas::default()
+        };
+        let owner = TestUser::new(&db, "owner");
+        let member = TestUser::new(&db, "member");
+        let late = TestUser::new(&db, "late");
+        let group_id = group(&db, &owner);
+        join(&db, group_id, &owner, &member);
+
+        let params = serde_json::from_value(serde_json::json!({
+            "group_id": group_id,
+            "user_id": late.id,
+        }))
+        .unwrap();
+        let response = run(crate::invitation::create(
+            owner.session(),
+            db.clone(),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert!(response.status().is_success());
+        let params = serde_json::from_value(serde_json::json!({ "group_id": group_id })).unwrap();
+        let response = run(crate::invitation::accept(
+            late.session(),
+            db.clone(),
+            crate::test_util::calendar(),
+            web::Data::new(quotas),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert_eq!(response.status(), 403);
+        assert_eq!(json::<String>(&response), "Members");
+    }
+
+    #[test]
+    fn created_activities_keep_one_entry_per_user() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let group_id = group(&db, &owner);
+        activity(&db, group_id, &owner, 86400);
+        activity(&db, group_id, &owner, 2 * 86400);
+
+        let activities_created_tree = db.open_tree(ACTIVITIES_CREATED_TREE).unwrap();
+        assert_eq!(activities_created_tree.len(), 1);
+        let value = activities_created_tree
+            .get(owner.id.to_be_bytes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(u64::from_be_bytes(value[8..16].try_into().unwrap()), 2);
+
+        let start = now() + 3 * 86400;
+        let params = serde_json::from_value(serde_json::json!({
+            "group_id": group_id,
+            "block": {"start": start, "end": start + 3600},
+            "description": "Activity",
+        }))
+        .unwrap();
+        let response = run(crate::activity::create(
+            owner.session(),
+            db.clone(),
+            crate::test_util::calendar(),
+            web::Data::new(Quotas {
+                activities_per_day: 2,
+                ..Quotas::default()
+            }),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert_eq!(response.status(), 429);
+        assert_eq!(json::<String>(&response), "ActivitiesPerDay");
+    }
+}
@@ -0,0 +1,332 @@
+use crate::{
+    group::{Group, GroupParams, GroupUserParams, Groups, Members},
+    holiday::Calendar,
+    notification::Event,
+    quota::Quotas,
+    role::{Permission, Role},
+    session::Session,
+    util::{abort_response, now, Abort, Error},
+};
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionalTree},
+    Transactional,
+};
+use std::{collections::HashMap, convert::TryInto};
+
+pub const JOIN_REQUESTS_TREE: &[u8] = b"join_requests";
+
+/// A request of a user to join a public group.
+#[derive(Serialize, Deserialize)]
+pub struct JoinRequest {
+    time: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+/// The users asking to join a group, since transactions can't scan.
+pub fn of_group(db: &sled::Db, group_id: u64) -> Result<Vec<u64>, Error> {
+    let join_requests_tree = db.open_tree(JOIN_REQUESTS_TREE)?;
+    join_requests_tree
+        .scan_prefix(group_id.to_be_bytes())
+        .keys()
+        .map(|k| Ok(u64::from_be_bytes(k?[8..16].try_into().unwrap())))
+        .collect()
+}
+
+/// Deletes the join requests for a group returned by `of_group`.
+pub fn delete_all(
+    join_requests_tree: &TransactionalTree,
+    group_id: u64,
+    user_ids: &[u64],
+) -> Result<(), ConflictableTransactionError<Abort>> {
+    for &user_id in user_ids {
+        let mut key = Vec::with_capacity(16);
+        key.extend_from_slice(&group_id.to_be_bytes());
+        key.extend_from_slice(&user_id.to_be_bytes());
+        join_requests_tree.remove(key)?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct NewJoinRequest {
+    group_id: u64,
+    message: Option<String>,
+}
+
+pub async fn create(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Json<NewJoinRequest>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    let join_requests_tree = db.open_tree(JOIN_REQUESTS_TREE)?;
+    let join_request = serde_json::to_vec(&JoinRequest {
+        time: now(),
+        message: params.message.clone(),
+    })?;
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&params.group_id.to_be_bytes());
+    key.extend_from_slice(&user_id.to_be_bytes());
+
+    let result = (&groups_tree, &group_members_tree, &join_requests_tree).transaction(
+        |(groups_tree, group_members_tree, join_requests_tree)| {
+            let group = crate::group::bump_version(groups_tree, params.group_id)?;
+            if !group.settings.public {
+                sled::transaction::abort(Abort::NotFound)?;
+            }
+            if group.archived {
+                sled::transaction::abort(Abort::NotAllowed)?;
+            }
+            if group_members_tree.role(params.group_id, user_id)?.is_some() {
+                sled::transaction::abort(Abort::Conflict(Default::default()))?;
+            }
+            if !crate::group::in_parent(group_members_tree, &group, user_id)? {
+                sled::transaction::abort(Abort::NotAllowed)?;
+            }
+            join_requests_tree.insert(key.as_slice(), join_request.as_slice())?;
+            Ok(())
+        },
+    );
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(sled::transaction::TransactionError::Abort(Abort::Conflict(_))) => {
+            Ok(HttpResponse::Conflict().finish())
+        }
+        Err(err) => abort_response(err),
+    }
+}
+
+pub async fn list(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Query<GroupParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    let join_requests_tree = db.open_tree(JOIN_REQUESTS_TREE)?;
+    let group = match groups_tree.get(params.group_id.to_be_bytes())? {
+        Some(group) => group,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let group: Group = serde_json::from_slice(&group)?;
+    match crate::group::role_in(
+        &groups_tree,
+        &group_members_tree,
+        params.group_id,
+        &group,
+        user_id,
+    )? {
+        Some(role) if role.can(Permission::ManageMembers) => (),
+        Some(_) => return Ok(HttpResponse::Forbidden().finish()),
+        None => return Ok(HttpResponse::NotFound().finish()),
+    }
+    let join_requests = join_requests_tree
+        .scan_prefix(params.group_id.to_be_bytes())
+        .map(|res| -> Result<(u64, JoinRequest), Error> {
+            let (k, v) = res?;
+            let user_id = u64::from_be_bytes(k[8..16].try_into().unwrap());
+            Ok((user_id, serde_json::from_slice(&v)?))
+        })
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    Ok(HttpResponse::Ok().json(join_requests))
+}
+
+pub async fn accept(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    calendar: web::Data<Calendar>,
+    quotas: web::Data<Quotas>,
+    params: web::Json<GroupUserParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let groups_user_tree = db.open_tree(crate::group::GROUPS_USER_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    let join_requests_tree = db.open_tree(JOIN_REQUESTS_TREE)?;
+    let notifications_tree = db.open_tree(crate::notification::NOTIFICATIONS_TREE)?;
+    let activities_tree = db.open_tree(crate::activity::ACTIVITIES_TREE)?;
+    let activities_user_tree = db.open_tree(crate::activity::ACTIVITIES_USER_TREE)?;
+    let activities_conflict_tree = db.open_tree(crate::activity::ACTIVITIES_CONFLICT_TREE)?;
+    let activities_participants_tree =
+        db.open_tree(crate::activity::ACTIVITIES_PARTICIPANTS_TREE)?;
+    let users_tree = db.open_tree(crate::user::USERS_TREE)?;
+    let templates_tree = db.open_tree(crate::template::TEMPLATES_TREE)?;
+    let holidays_user_tree = db.open_tree(crate::holiday::HOLIDAYS_USER_TREE)?;
+    let audit_log_tree = db.open_tree(crate::audit::AUDIT_LOG_TREE)?;
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&params.group_id.to_be_bytes());
+    key.extend_from_slice(&params.user_id.to_be_bytes());
+
+    // The group may change between reading it and the transaction
+    let mut attempts = 0;
+    let result = loop {
+        attempts += 1;
+        let snapshot = crate::group::snapshot(&db, params.group_id)?;
+        let upcoming = crate::activity::upcoming(&db, params.group_id)?;
+        let result = (
+            &groups_tree,
+            &groups_user_tree,
+            &group_members_tree,
+            &join_requests_tree,
+            &notifications_tree,
+            &activities_tree,
+            &activities_user_tree,
+            &activities_conflict_tree,
+            &activities_participants_tree,
+            &users_tree,
+            &templates_tree,
+            &holidays_user_tree,
+            &audit_log_tree,
+        )
+            .transaction(
+                |(
+                    groups_tree,
+                    groups_user_tree,
+                    group_members_tree,
+                    join_requests_tree,
+                    notifications_tree,
+                    activities_tree,
+                    activities_user_tree,
+                    activities_conflict_tree,
+                    activities_participants_tree,
+                    users_tree,
+                    templates_tree,
+                    holidays_user_tree,
+                    audit_log_tree,
+                )| {
+                    let group = groups_tree.group(params.group_id)?.ok_or(
+                        sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
+                    )?;
+                    match crate::group::role_in(
+                        groups_tree,
+                        group_members_tree,
+                        params.group_id,
+                        &group,
+                        user_id,
+                    )? {
+                        Some(role) if role.can(Permission::ManageMembers) => (),
+                        Some(_) => sled::transaction::abort(Abort::NotAllowed)?,
+                        None => sled::transaction::abort(Abort::NotFound)?,
+                    }
+                    if join_requests_tree.remove(key.as_slice())?.is_none() {
+                        sled::transaction::abort(Abort::NotFound)?;
+                    }
+                    // The upcoming activities have to be complete
+                    crate::group::check_snapshot(groups_tree, params.group_id, &snapshot)?;
+                    if crate::group::add_member(
+                        groups_tree,
+                        groups_user_tree,
+                        group_members_tree,
+                        audit_log_tree,
+                        &quotas,
+                        params.group_id,
+                        params.user_id,
+                        Role::Member,
+                        user_id,
+                    )? {
+                        let availability = crate::activity::availability(
+                            users_tree,
+                            templates_tree,
+                            holidays_user_tree,
+                            &calendar,
+                            params.user_id,
+                        )?;
+                        crate::activity::add_participant(
+                            activities_tree,
+                            activities_user_tree,
+                            activities_conflict_tree,
+                            activities_participants_tree,
+                            params.user_id,
+                            &upcoming,
+                            &availability,
+                        )?;
+                    }
+                    crate::notification::notify(
+                        notifications_tree,
+                        params.user_id,
+                        Event::JoinRequestAccepted {
+                            group_id: params.group_id,
+                        },
+                    )
+                },
+            );
+        if attempts == crate::group::ATTEMPTS
+            || !matches!(
+                result,
+                Err(sled::transaction::TransactionError::Abort(Abort::Stale))
+            )
+        {
+            break result;
+        }
+    };
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => abort_response(err),
+    }
+}
+
+/// Rejects a join request, or withdraws it if the logged in user filed it.
+pub async fn reject(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    params: web::Json<GroupUserParams>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let group_members_tree = db.open_tree(crate::group::GROUP_MEMBERS_TREE)?;
+    let join_requests_tree = db.open_tree(JOIN_REQUESTS_TREE)?;
+    let notifications_tree = db.open_tree(crate::notification::NOTIFICATIONS_TREE)?;
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&params.group_id.to_be_bytes());
+    key.extend_from_slice(&params.user_id.to_be_bytes());
+
+    let result = (
+        &groups_tree,
+        &group_members_tree,
+        &join_requests_tree,
+        &notifications_tree,
+    )
+        .transaction(
+            |(groups_tree, group_members_tree, join_requests_tree, notifications_tree)| {
+                if user_id != params.user_id {
+                    let group = groups_tree.group(params.group_id)?.ok_or(
+                        sled::transaction::ConflictableTransactionError::Abort(Abort::NotFound),
+                    )?;
+                    match crate::group::role_in(
+                        groups_tree,
+                        group_members_tree,
+                        params.group_id,
+                        &group,
+                        user_id,
+                    )? {
+                        Some(role) if role.can(Permission::ManageMembers) => (),
+                        Some(_) => sled::transaction::abort(Abort::NotAllowed)?,
+                        None => sled::transaction::abort(Abort::NotFound)?,
+                    }
+                }
+                if join_requests_tree.remove(key.as_slice())?.is_none() {
+                    sled::transaction::abort(Abort::NotFound)?;
+                }
+                if user_id != params.user_id {
+                    crate::notification::notify(
+                        notifications_tree,
+                        params.user_id,
+                        Event::JoinRequestRejected {
+                            group_id: params.group_id,
+                        },
+                    )?;
+                }
+                Ok(())
+            },
+        );
+    match result {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(err) => abort_response(err),
+    }
+}
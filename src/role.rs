@@ -0,0 +1,109 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+
+/// The role of a member in a group, ordered by rank.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Role {
+    Viewer,
+    #[default]
+    Member,
+    Organizer,
+    Admin,
+    Owner,
+}
+
+#[derive(Clone, Copy)]
+pub enum Permission {
+    /// Take part in activities of the group.
+    Participate,
+    /// Create activities for the group.
+    CreateActivity,
+    /// Invite users, manage invite codes and remove members of lower rank.
+    ManageMembers,
+    /// Give members of lower rank a role up to the own one.
+    ManageRoles,
+    /// Change the details and settings of the group.
+    ManageGroup,
+    /// Hand the group over to another member.
+    TransferOwnership,
+    /// Create activities without approval and approve or reject proposed
+    /// ones.
+    ApproveActivities,
+    /// Delete the group along with its activities.
+    DeleteGroup,
+    /// Read the log of changes to the group.
+    ViewLog,
+    /// Merge the group with another group managed by the same member.
+    MergeGroup,
+    /// Change the details, time and participant limits of activities.
+    EditActivities,
+}
+
+impl Role {
+    pub fn can(self, permission: Permission) -> bool {
+        match permission {
+            Permission::Participate => self >= Role::Member,
+            Permission::CreateActivity => self >= Role::Member,
+            Permission::ManageMembers => self >= Role::Admin,
+            Permission::ManageRoles => self >= Role::Admin,
+            Permission::ManageGroup => self >= Role::Admin,
+            Permission::TransferOwnership => self == Role::Owner,
+            Permission::ApproveActivities => self >= Role::Admin,
+            Permission::DeleteGroup => self == Role::Owner,
+            Permission::ViewLog => self >= Role::Admin,
+            Permission::MergeGroup => self >= Role::Admin,
+            Permission::EditActivities => self >= Role::Organizer,
+        }
+    }
+
+    /// Whether a member with this role may give `role` to someone.
+    /// Ownership can only be transferred.
+    pub fn can_grant(self, role: Role) -> bool {
+        role <= self
+            && role != Role::Owner
+            && (role <= Role::Member || self.can(Permission::ManageRoles))
+    }
+
+    /// Whether a member with this role may give `role` to a member which
+    /// currently has the role `current`.
+    pub fn can_assign(self, current: Role, role: Role) -> bool {
+        self.can(Permission::ManageRoles) && current < self && self.can_grant(role)
+    }
+}
+
+/// Roles used to be stored as a boolean which was `true` for admins.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredRole {
+    Role(Role),
+    Admin(bool),
+}
+
+impl From<StoredRole> for Role {
+    fn from(role: StoredRole) -> Self {
+        match role {
+            StoredRole::Role(role) => role,
+            StoredRole::Admin(true) => Role::Admin,
+            StoredRole::Admin(false) => Role::Member,
+        }
+    }
+}
+
+pub fn deserialize_role<'de, D>(deserializer: D) -> Result<Role, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let role: StoredRole = Deserialize::deserialize(deserializer)?;
+    Ok(role.into())
+}
+
+pub fn deserialize_roles<'de, D>(deserializer: D) -> Result<HashMap<u64, Role>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let roles: HashMap<u64, StoredRole> = Deserialize::deserialize(deserializer)?;
+    Ok(roles
+        .into_iter()
+        .map(|(user_id, role)| (user_id, role.into()))
+        .collect())
+}
@@ -0,0 +1,206 @@
+use crate::{
+    block::{Block, Interval},
+    holiday::{Holidays, Mode},
+    session::Session,
+    util::Error,
+};
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::value::RawValue;
+
+pub const TEMPLATES_TREE: &[u8] = b"templates";
+
+const DAY: i128 = 24 * 60 * 60;
+
+/// The longest interval quiet hours are generated for, a year. Weekly quiet
+/// hours and yearly holidays all occur within it.
+pub const MAX_WINDOW: u64 = 366 * DAY as u64;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl Weekday {
+    /// The weekday of a day counted from 1970-01-01, which was a thursday.
+    pub fn from_day(day: i64) -> Weekday {
+        match (day + 3).rem_euclid(7) {
+            0 => Weekday::Monday,
+            1 => Weekday::Tuesday,
+            2 => Weekday::Wednesday,
+            3 => Weekday::Thursday,
+            4 => Weekday::Friday,
+            5 => Weekday::Saturday,
+            _ => Weekday::Sunday,
+        }
+    }
+}
+
+/// Recurring time in which a user is never available. `start` and `end` are
+/// minutes after midnight in the users local time. If `end` is not after
+/// `start` the quiet hours reach into the next day.
+#[derive(Serialize, Clone)]
+pub struct QuietHours {
+    days: Vec<Weekday>,
+    start: u32,
+    end: u32,
+}
+
+impl<'de> Deserialize<'de> for QuietHours {
+    fn deserialize<D>(deserializer: D) -> Result<QuietHours, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct DQuietHours {
+            days: Vec<Weekday>,
+            start: u32,
+            end: u32,
+        }
+        let quiet: DQuietHours = Deserialize::deserialize(deserializer)?;
+        if quiet.start < 24 * 60 && quiet.end <= 24 * 60 && quiet.start != quiet.end {
+            Ok(QuietHours {
+                days: quiet.days,
+                start: quiet.start,
+                end: quiet.end,
+            })
+        } else {
+            Err(serde::de::Error::custom(
+                "Quiet hours must be distinct minutes of a day",
+            ))
+        }
+    }
+}
+
+/// Weekly availability of a user, applied as implicit blocks without storing
+/// them in the users block list.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Template {
+    /// Offset of the users local time to UTC in minutes.
+    #[serde(default)]
+    pub utc_offset: i32,
+    #[serde(default)]
+    quiet: Vec<QuietHours>,
+    /// Seconds to keep free around activities, e.g. for travelling.
+    #[serde(default)]
+    pub buffer: u64,
+}
+
+impl Template {
+    pub fn from_value(value: Option<sled::IVec>) -> serde_json::Result<Template> {
+        match value {
+            Some(template) => serde_json::from_slice(&template),
+            None => Ok(Template::default()),
+        }
+    }
+
+    /// The quiet hours intersecting the given interval as hard blocks.
+    /// Holidays are either blocked as a whole or free of quiet hours. Only the
+    /// first `MAX_WINDOW` seconds of longer intervals are covered.
+    pub fn blocks(&self, window: &Interval, holidays: &Holidays) -> Vec<Block> {
+        let offset = self.utc_offset as i128 * 60;
+        let end = window.end().min(window.start().saturating_add(MAX_WINDOW));
+        let window = Interval::new(window.start(), end);
+        let first = (window.start() as i128 + offset).div_euclid(DAY) as i64 - 1;
+        let last = (window.end() as i128 + offset).div_euclid(DAY) as i64;
+        let mut blocks = Vec::new();
+        let mut push = |start: i128, end: i128| {
+            if end > 0 {
+                let block = Block::new(start.max(0) as u64, end.min(u64::MAX as i128) as u64);
+                if window.intersects(&block.interval()) {
+                    blocks.push(block);
+                }
+            }
+        };
+        for day in first..=last {
+            let midnight = day as i128 * DAY - offset;
+            match holidays.on(day) {
+                Some(Mode::Free) => continue,
+                Some(Mode::Blocked) => {
+                    push(midnight, midnight + DAY);
+                    continue;
+                }
+                None => (),
+            }
+            let weekday = Weekday::from_day(day);
+            for quiet in self.quiet.iter().filter(|q| q.days.contains(&weekday)) {
+                let start = midnight + quiet.start as i128 * 60;
+                let mut end = midnight + quiet.end as i128 * 60;
+                if quiet.end <= quiet.start {
+                    end += DAY;
+                }
+                push(start, end);
+            }
+        }
+        blocks
+    }
+}
+
+pub async fn set(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+    template: web::Json<Template>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let templates_tree = db.open_tree(TEMPLATES_TREE)?;
+    let template = serde_json::to_vec(&template.into_inner())?;
+    templates_tree.insert(user_id.to_be_bytes(), template)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn get(
+    session: web::Query<Session>,
+    db: web::Data<sled::Db>,
+) -> Result<HttpResponse, Error> {
+    let user_id: u64 = session.get(&db)?;
+    let templates_tree = db.open_tree(TEMPLATES_TREE)?;
+    match templates_tree.get(user_id.to_be_bytes())? {
+        Some(template) => {
+            let template: Box<RawValue> = serde_json::from_slice(&template)?;
+            Ok(HttpResponse::Ok().json(template))
+        }
+        None => Ok(HttpResponse::Ok().json(Template::default())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::holiday::Calendar;
+
+    fn nightly() -> Template {
+        serde_json::from_str(
+            r#"{"utc_offset": 60, "quiet": [{"days": ["Monday", "Tuesday", "Wednesday",
+                "Thursday", "Friday", "Saturday", "Sunday"], "start": 1380, "end": 360}]}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn blocks_cover_at_most_max_window() {
+        let calendar = Calendar::load();
+        let holidays = calendar.holidays(None).unwrap();
+        let blocks = nightly().blocks(&Interval::new(0, u64::MAX), &holidays);
+        assert!(!blocks.is_empty());
+        assert!(blocks.len() <= 368);
+        assert!(blocks
+            .iter()
+            .all(|b| b.interval().end() <= MAX_WINDOW + DAY as u64));
+    }
+
+    #[test]
+    fn blocks_near_the_end_of_time_do_not_overflow() {
+        let calendar = Calendar::load();
+        let holidays = calendar.holidays(None).unwrap();
+        let window = Interval::new(u64::MAX - 7 * DAY as u64, u64::MAX);
+        let blocks = nightly().blocks(&window, &holidays);
+        assert!(!blocks.is_empty());
+        assert!(blocks.iter().all(|b| window.intersects(&b.interval())));
+    }
+}
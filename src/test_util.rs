@@ -0,0 +1,120 @@
+//! Helpers for tests which call the handlers directly.
+
+use crate::{holiday::Calendar, quota::Quotas, session::Session, user::User};
+use actix_web::{dev::Body, web, HttpResponse};
+use serde::de::DeserializeOwned;
+use std::future::Future;
+
+pub fn db() -> web::Data<sled::Db> {
+    web::Data::new(sled::Config::new().temporary(true).open().unwrap())
+}
+
+pub fn calendar() -> web::Data<Calendar> {
+    web::Data::new(Calendar::load())
+}
+
+pub fn quotas() -> web::Data<Quotas> {
+    web::Data::new(Quotas::default())
+}
+
+/// Runs a handler to completion.
+pub fn run<F: Future + 'static>(future: F) -> F::Output {
+    actix_web::rt::System::new("test").block_on(future)
+}
+
+/// A registered user with a session.
+pub struct TestUser {
+    pub id: u64,
+    token: String,
+}
+
+impl TestUser {
+    pub fn new(db: &sled::Db, username: &str) -> TestUser {
+        let id = db.generate_id().unwrap();
+        let user = serde_json::to_vec(&User {
+            username: username.to_string(),
+            blocks: Vec::new(),
+        })
+        .unwrap();
+        db.open_tree(crate::user::USERS_TREE)
+            .unwrap()
+            .insert(id.to_be_bytes(), user)
+            .unwrap();
+        let token = Session::new(db, id).unwrap().token;
+        TestUser { id, token }
+    }
+
+    pub fn session(&self) -> web::Query<Session> {
+        web::Query(Session {
+            token: self.token.clone(),
+        })
+    }
+}
+
+/// The JSON body of a response.
+pub fn json<T: DeserializeOwned>(response: &HttpResponse) -> T {
+    match response.body().as_ref() {
+        Some(Body::Bytes(bytes)) => serde_json::from_slice(bytes).unwrap(),
+        _ => panic!("Response has no JSON body"),
+    }
+}
+
+/// Creates a group owned by `owner`.
+pub fn group(db: &web::Data<sled::Db>, owner: &TestUser) -> u64 {
+    let response = run(crate::group::create(
+        owner.session(),
+        db.clone(),
+        quotas(),
+        web::Json("Group".to_string()),
+    ))
+    .unwrap();
+    json(&response)
+}
+
+/// Makes `user` a member of a group by having `by` invite them.
+pub fn join(db: &web::Data<sled::Db>, group_id: u64, by: &TestUser, user: &TestUser) {
+    let params = serde_json::from_value(serde_json::json!({
+        "group_id": group_id,
+        "user_id": user.id,
+    }))
+    .unwrap();
+    let response = run(crate::invitation::create(
+        by.session(),
+        db.clone(),
+        web::Json(params),
+    ))
+    .unwrap();
+    assert!(response.status().is_success());
+    let params = serde_json::from_value(serde_json::json!({ "group_id": group_id })).unwrap();
+    let response = run(crate::invitation::accept(
+        user.session(),
+        db.clone(),
+        calendar(),
+        quotas(),
+        web::Json(params),
+    ))
+    .unwrap();
+    assert!(response.status().is_success());
+}
+
+/// Creates an activity in a group, starting `start` seconds from now and
+/// lasting an hour.
+pub fn activity(db: &web::Data<sled::Db>, group_id: u64, by: &TestUser, start: u64) -> u64 {
+    let start = crate::util::now() + start;
+    let params = serde_json::from_value(serde_json::json!({
+        "group_id": group_id,
+        "block": {"start": start, "end": start + 3600},
+        "description": "Activity",
+    }))
+    .unwrap();
+    let response = run(crate::activity::create(
+        by.session(),
+        db.clone(),
+        calendar(),
+        quotas(),
+        web::Json(params),
+    ))
+    .unwrap();
+    assert_eq!(response.status(), 200);
+    json(&response)
+}
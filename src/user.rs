@@ -1,4 +1,9 @@
-use crate::{block::Block, session::Session, util::Error};
+use crate::{
+    block::Block,
+    session::Session,
+    template::Template,
+    util::{abort_response, Abort, Conflicts, Error},
+};
 use actix_web::{web, HttpResponse};
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::value::RawValue;
@@ -116,40 +121,107 @@ pub async fn get(
     Ok(HttpResponse::Ok().json(user))
 }
 
+#[derive(Deserialize)]
+pub struct BlockOptions {
+    #[serde(default)]
+    deny_pending: bool,
+    #[serde(default)]
+    keep_accepted: bool,
+}
+
 pub async fn add_block(
     db: web::Data<sled::Db>,
     session: web::Query<Session>,
+    options: web::Query<BlockOptions>,
     block: web::Json<Block>,
 ) -> Result<HttpResponse, Error> {
-    let block = block.into_inner();
     let user_id: u64 = session.get(&db)?;
-    let users_tree = db.open_tree(USERS_TREE)?;
-    let result = users_tree.transaction(|users_tree| {
-        let user = users_tree
-            .get(user_id.to_be_bytes())?
-            .expect("Missing user_id");
-        let mut user: User = serde_json::from_slice(&user)
-            .map_err(|err| sled::transaction::ConflictableTransactionError::Abort(Some(err)))?;
-        if user.blocks.iter().any(|b| block.intersects(b)) {
-            sled::transaction::abort(None)
-        } else {
-            user.blocks.push(block.clone());
-            let user = serde_json::to_vec(&user)
-                .map_err(|err| sled::transaction::ConflictableTransactionError::Abort(Some(err)))?;
-            users_tree.insert(&user_id.to_be_bytes(), user)?;
-            Ok(())
-        }
-    });
-    match result {
-        Ok(()) => Ok(HttpResponse::Ok().finish()),
-        Err(sled::transaction::TransactionError::Storage(err)) => Err(Error::SledError(err)),
-        Err(sled::transaction::TransactionError::Abort(abort)) => match abort {
-            None => Ok(HttpResponse::Conflict().finish()),
-            Some(err) => Err(Error::SerdeError(err)),
-        },
+    match add_blocks(&db, user_id, &[block.into_inner()], &options)? {
+        Ok(remaining) => Ok(HttpResponse::Ok().json(remaining)),
+        Err(err) => abort_response(err),
     }
 }
 
+/// Adds blocks to a user and re-evaluates the activities of the user they
+/// intersect. Returns the activities which still intersect one of the blocks.
+pub fn add_blocks(
+    db: &sled::Db,
+    user_id: u64,
+    blocks: &[Block],
+    options: &BlockOptions,
+) -> Result<sled::transaction::TransactionResult<Vec<u64>, Abort>, Error> {
+    let users_tree = db.open_tree(USERS_TREE)?;
+    let activities_tree = db.open_tree(crate::activity::ACTIVITIES_TREE)?;
+    let activities_user_tree = db.open_tree(crate::activity::ACTIVITIES_USER_TREE)?;
+    let activities_conflict_tree = db.open_tree(crate::activity::ACTIVITIES_CONFLICT_TREE)?;
+    let templates_tree = db.open_tree(crate::template::TEMPLATES_TREE)?;
+    let groups_tree = db.open_tree(crate::group::GROUPS_TREE)?;
+    let template = Template::from_value(templates_tree.get(user_id.to_be_bytes())?)?;
+    let activity_ids = blocks
+        .iter()
+        .map(|block| crate::activity::intersecting(db, user_id, &block.interval(), template.buffer))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((
+        &users_tree,
+        &activities_tree,
+        &activities_user_tree,
+        &activities_conflict_tree,
+        &groups_tree,
+    )
+        .transaction(
+            |(
+                users_tree,
+                activities_tree,
+                activities_user_tree,
+                activities_conflict_tree,
+                groups_tree,
+            )| {
+                let user = users_tree
+                    .get(user_id.to_be_bytes())?
+                    .expect("Missing user_id");
+                let mut user: User = serde_json::from_slice(&user).map_err(|err| {
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(err))
+                })?;
+                let mut remaining = Vec::new();
+                for (block, activity_ids) in blocks.iter().zip(&activity_ids) {
+                    let intersecting: Vec<Block> = user
+                        .blocks
+                        .iter()
+                        .filter(|b| block.intersects(b))
+                        .cloned()
+                        .collect();
+                    if !intersecting.is_empty() {
+                        sled::transaction::abort(Abort::Conflict(Conflicts {
+                            blocks: intersecting,
+                            activities: Vec::new(),
+                            groups: Vec::new(),
+                        }))?;
+                    }
+                    remaining.extend(crate::activity::block_added(
+                        activities_tree,
+                        activities_user_tree,
+                        activities_conflict_tree,
+                        groups_tree,
+                        user_id,
+                        activity_ids,
+                        block,
+                        template.buffer,
+                        options.deny_pending,
+                        options.keep_accepted,
+                    )?);
+                    user.blocks.push(block.clone());
+                }
+                remaining.sort_unstable();
+                remaining.dedup();
+                let user = serde_json::to_vec(&user).map_err(|err| {
+                    sled::transaction::ConflictableTransactionError::Abort(Abort::SerdeError(err))
+                })?;
+                users_tree.insert(&user_id.to_be_bytes(), user)?;
+                Ok(remaining)
+            },
+        ))
+}
+
 pub async fn remove_block(
     db: web::Data<sled::Db>,
     session: web::Query<Session>,
@@ -167,7 +239,7 @@ pub async fn remove_block(
         let index = user
             .blocks
             .iter()
-            .position(|b| b == &block)
+            .position(|b| b.same_time(&block))
             .ok_or(sled::transaction::ConflictableTransactionError::Abort(None))?;
         user.blocks.remove(index);
         let user = serde_json::to_vec(&user)
@@ -184,3 +256,52 @@ pub async fn remove_block(
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{activity, db, group, join, run, TestUser};
+
+    #[test]
+    fn blocks_leave_archived_activities_alone() {
+        let db = db();
+        let owner = TestUser::new(&db, "owner");
+        let member = TestUser::new(&db, "member");
+        let group_id = group(&db, &owner);
+        join(&db, group_id, &owner, &member);
+        let activity_id = activity(&db, group_id, &owner, 86400);
+        let params = serde_json::from_value(serde_json::json!({ "group_id": group_id })).unwrap();
+        let response = run(crate::group::archive(
+            owner.session(),
+            db.clone(),
+            web::Json(params),
+        ))
+        .unwrap();
+        assert!(response.status().is_success());
+
+        let options = serde_json::from_value(serde_json::json!({ "deny_pending": true })).unwrap();
+        let now = crate::util::now();
+        let response = run(add_block(
+            db.clone(),
+            member.session(),
+            web::Query(options),
+            web::Json(Block::new(now, now + 2 * 86400)),
+        ))
+        .unwrap();
+        assert!(response.status().is_success());
+        let mut key = member.id.to_be_bytes().to_vec();
+        key.extend_from_slice(&activity_id.to_be_bytes());
+        let status = db
+            .open_tree(crate::activity::ACTIVITIES_USER_TREE)
+            .unwrap()
+            .get(&key)
+            .unwrap()
+            .unwrap();
+        assert_eq!(status.as_ref(), b"\"Pending\"");
+        assert!(!db
+            .open_tree(crate::activity::ACTIVITIES_CONFLICT_TREE)
+            .unwrap()
+            .contains_key(&key)
+            .unwrap());
+    }
+}
@@ -1,9 +1,71 @@
+use crate::{block::Block, quota::Quota};
 use actix_web::HttpResponse;
+use serde::Serialize;
+use sled::transaction::TransactionError;
 
 pub enum Abort {
     NotFound,
     NotAllowed,
+    Conflict(Conflicts),
+    QuotaExceeded(Quota),
     SerdeError(serde_json::Error),
+    /// Data read before the transaction changed in the meantime.
+    Stale,
+}
+
+/// Blocks, activities and groups standing in the way of a request, sent with
+/// CONFLICT responses.
+#[derive(Serialize, Default)]
+pub struct Conflicts {
+    pub blocks: Vec<Block>,
+    pub activities: Vec<u64>,
+    /// Groups whose last admin may not leave.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<u64>,
+}
+
+/// The response to a failed transaction.
+pub fn abort_response(err: TransactionError<Abort>) -> Result<HttpResponse, Error> {
+    match err {
+        TransactionError::Storage(err) => Err(Error::SledError(err)),
+        TransactionError::Abort(abort) => match abort {
+            Abort::NotFound => Ok(HttpResponse::NotFound().finish()),
+            Abort::NotAllowed => Ok(HttpResponse::Forbidden().finish()),
+            Abort::Conflict(conflicts) => Ok(HttpResponse::Conflict().json(conflicts)),
+            Abort::QuotaExceeded(quota) if quota.resets_daily() => {
+                Ok(HttpResponse::TooManyRequests().json(quota))
+            }
+            Abort::QuotaExceeded(quota) => Ok(HttpResponse::Forbidden().json(quota)),
+            Abort::SerdeError(err) => Err(Error::SerdeError(err)),
+            Abort::Stale => Ok(HttpResponse::Conflict().finish()),
+        },
+    }
+}
+
+pub const MIGRATIONS_TREE: &[u8] = b"migrations";
+
+/// Runs a migration unless it has already been applied to the database, and
+/// marks it as applied.
+pub fn migrate_once(
+    db: &sled::Db,
+    name: &str,
+    migration: impl FnOnce(&sled::Db) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let migrations_tree = db.open_tree(MIGRATIONS_TREE)?;
+    if migrations_tree.contains_key(name)? {
+        return Ok(());
+    }
+    migration(db)?;
+    migrations_tree.insert(name, &now().to_be_bytes())?;
+    Ok(())
+}
+
+/// Seconds since the unix epoch.
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
 }
 
 #[derive(Debug)]
@@ -59,3 +121,22 @@ impl actix_web::error::ResponseError for Error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_run_once() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let runs = std::cell::Cell::new(0);
+        for _ in 0..2 {
+            migrate_once(&db, "test", |_| {
+                runs.set(runs.get() + 1);
+                Ok(())
+            })
+            .unwrap();
+        }
+        assert_eq!(runs.get(), 1);
+    }
+}